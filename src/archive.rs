@@ -1,137 +1,649 @@
 
 
-use std::fs::{File, OpenOptions, read};
+use std::fs::{File, OpenOptions};
 use std::path::{PathBuf, Path};
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
 use std::time::SystemTime;
-use std::io::{Read, Write, Seek, SeekFrom, Take};
+use std::io::{Read, Write, Seek, SeekFrom};
 use std::collections::{HashMap};
-use std::borrow::Borrow;
-use lzma_rs::{lzma_compress, lzma_decompress};
+use sha2::{Sha256, Digest};
+use log::{debug, info, warn};
+use crate::error::{ArchiveError, Result};
+use chunks::{Chunk, ChunkHeader, Chunker, read_chunk};
+
+mod verify;
+mod reader;
+mod compression;
+mod chunks;
+mod diff;
+#[cfg(test)]
+mod tests;
+
+pub use verify::{VerifyReport, Problem, ProblemKind};
+pub use reader::FileReader;
+pub use compression::{Compression, CompressionRules};
+pub use diff::{Change, ChangeKind, Revision};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 enum Contents {
     Snapshot,
-    Patch
+    //A binary delta against the file header at offset `base`, `depth` patches away from a snapshot
+    Patch { base: u64, depth: u32 },
+    //No payload follows the header, used for directories
+    Empty,
+    //A symbolic link to `target`, no payload follows the header
+    Link { target: PathBuf },
+    //The same contents as the earlier file header at offset `target`, no payload follows the header
+    Duplicate { target: u64 },
+    //Split into chunks stored once each in the archive, in order. No payload follows the header
+    Chunked { chunks: Vec<Chunk> },
+    //The path was removed in this version, no payload follows the header
+    Deleted,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-enum FileType {
+//Decides whether `AppendArchive::append` stores a file as a snapshot, a patch or in chunks
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct PatchPolicy {
+    //Longest chain of patches allowed before a snapshot is forced, 0 disables patching
+    pub max_chain_length: u32,
+    //Largest compressed patch allowed, as a percentage of the compressed snapshot
+    pub max_patch_ratio: u32,
+    //Files at least this long are split into chunks instead, 0 disables chunking
+    pub min_chunked_len: u64,
+}
+
+impl Default for PatchPolicy {
+    fn default() -> Self {
+        PatchPolicy {
+            max_chain_length: 16,
+            max_patch_ratio: 50,
+            min_chunked_len: 4 * 1024 * 1024,
+        }
+    }
+}
+
+//How an incremental append decides whether a file has changed since the previous version
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangeDetection {
+    //Compare the type, length, modification time and read-only flag
+    Metadata,
+    //Compare the metadata and the hash of the contents, catching changes that kept the time
+    Hash,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum FileType {
     File,
     Directory,
     SystemLink,
 }
 
+//Metadata stored with every entry and applied when it is restored. Times that are unknown are left
+//as they are on restore
 #[derive(Serialize, Deserialize, Debug, Clone)]
-struct Metadata {
-    file_type: FileType,
-    len: u64,
-    read_only: bool,
-    modified: Option<SystemTime>,
-    accessed: Option<SystemTime>,
-    created: Option<SystemTime>,
+pub struct Metadata {
+    pub file_type: FileType,
+    pub len: u64, //Uncompressed length, filled in from the contents when a file is appended
+    pub read_only: bool,
+    pub modified: Option<SystemTime>,
+    pub accessed: Option<SystemTime>,
+    pub created: Option<SystemTime>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct FileHeader {
     compressed_size: u64,
+    compression: Compression,
+    hash: [u8; 32], //SHA-256 of the uncompressed contents
     metadata: Metadata,
     path: PathBuf,
     contents: Contents,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+//Identifies a version. Numbers are unique within an archive and increase with every append
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct VersionNumber {
     pub number: u64
 }
 
+impl VersionNumber {
+    //The number following this one, used when numbers are assigned automatically
+    pub fn next(& self) -> Self {
+        VersionNumber { number: self.number + 1 }
+    }
+}
+
+impl From<u64> for VersionNumber {
+    fn from(number: u64) -> Self {
+        VersionNumber { number }
+    }
+}
+
+impl std::fmt::Display for VersionNumber {
+    fn fmt(& self, f: & mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.number)
+    }
+}
+
+//An entry in the path table of a version header, giving the offset of the file header and the
+//sizes of the entry so it can be found and listed without reading the header
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct IndexEntry {
+    path: PathBuf,
+    offset: u64,
+    len: u64,
+    compressed_size: u64,
+    deleted: bool, //A tombstone, so it isn't inherited by incremental versions
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct VersionHeader {
-    files: HashMap<PathBuf, u64>,
+    files: Vec<IndexEntry>, //Sorted by path, so entries can be found with a binary search
     number: VersionNumber,
     message: String,
+    policy: PatchPolicy,
 }
 
 impl VersionHeader {
     fn new(number: VersionNumber, message: String) -> Self {
         VersionHeader {
-            files: HashMap::new(),
+            files: Vec::new(),
             number,
             message,
+            policy: PatchPolicy::default(),
+        }
+    }
+
+    //Add the entry for the header at `offset` with `compressed_size` bytes stored for it, replacing
+    //any earlier entry with the same path
+    fn insert(& mut self, header: & FileHeader, offset: u64, compressed_size: u64) {
+        self.insert_entry(IndexEntry {
+            path: header.path.clone(),
+            offset,
+            len: header.metadata.len,
+            compressed_size,
+            deleted: matches!(header.contents, Contents::Deleted),
+        });
+    }
+
+    fn insert_entry(& mut self, entry: IndexEntry) {
+        match self.files.binary_search_by(|probe| probe.path.cmp(&entry.path)) {
+            Ok(index) => self.files[index] = entry,
+            Err(index) => self.files.insert(index, entry),
         }
     }
 
-    fn insert(& mut self, path: & Path, offset: u64) {
-        self.files.insert(PathBuf::from(path), offset);
+    fn get(& self, path: & Path) -> Option<& IndexEntry> {
+        self.files.binary_search_by(|probe| probe.path.as_path().cmp(path))
+            .ok()
+            .map(|index| &self.files[index])
     }
 }
 
-#[derive(Debug, Clone)]
-struct Version {
-    pub files: HashMap<PathBuf, (u64, FileHeader)>,
-    pub number: VersionNumber,
-    pub message: String,
+//Identifies the file as an archive
+const MAGIC: [u8; 8] = *b"GUDARCHV";
+
+//Marks the start of every version directory, so they can be found again if the superblock is damaged
+const DIRECTORY_MAGIC: [u8; 8] = *b"GUDVRDIR";
+
+//Readers refuse archives with a different major version, minor versions only add optional features
+const FORMAT_MAJOR: u16 = 1;
+const FORMAT_MINOR: u16 = 0;
+
+//Required feature flags, set once the archive holds something older readers would misparse
+const REQUIRED_EMPTY_CONTENTS: u32 = 1 << 0; //Entries without a payload, such as directories
+const REQUIRED_LINK_CONTENTS: u32 = 1 << 1; //Symbolic links
+const REQUIRED_DUPLICATE_CONTENTS: u32 = 1 << 2; //Entries sharing the payload of an earlier entry
+const REQUIRED_CHUNKED_CONTENTS: u32 = 1 << 3; //Entries stored as chunks
+const REQUIRED_DELETED_CONTENTS: u32 = 1 << 4; //Tombstones for removed paths
+
+//Required feature flags understood by this version, archives using any others are refused
+const KNOWN_REQUIRED_FLAGS: u32 = REQUIRED_EMPTY_CONTENTS | REQUIRED_LINK_CONTENTS | REQUIRED_DUPLICATE_CONTENTS | REQUIRED_CHUNKED_CONTENTS | REQUIRED_DELETED_CONTENTS;
+
+//Maps the hash of every distinct contents stored in the archive to the file header holding them,
+//and of every distinct chunk to its chunk header, so identical data is only stored once. Each
+//version that stores something new appends a record holding only what it added, chained back to
+//the record before, so the index grows with the contents rather than with every version
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct ContentIndex {
+    previous: Option<u64>, //Offset of the record written before this one
+    contents: HashMap<[u8; 32], u64>,
+    chunks: HashMap<[u8; 32], u64>,
 }
 
-impl Version {
-    fn new(files: HashMap<PathBuf, (u64, FileHeader)>, number: VersionNumber, message: String) -> Self {
+impl ContentIndex {
+    //Read the chain of records ending at `offset`, merged into one index
+    fn load(fp: & mut File, archive_path: & Path, offset: Option<u64>) -> Result<Self> {
+        let mut index = ContentIndex::default();
+        let mut next = offset;
 
-        Version {
-            files,
-            number,
-            message,
+        while let Some(offset) = next {
+            fp.seek(SeekFrom::Start(offset)).map_err(ArchiveError::io(archive_path, Some(offset)))?;
+
+            let record: ContentIndex = read_record(fp, archive_path)?;
+
+            //Records only refer back, so the chain always ends
+            next = match record.previous {
+                Some(previous) if previous >= offset => {
+                    return Err(ArchiveError::Corrupt { path: PathBuf::from(archive_path), offset, reason: format!("content index refers forwards to offset {}", previous) });
+                }
+                previous => previous,
+            };
+
+            //A hash is only ever added by the first version to store it, so records never overlap
+            index.contents.extend(record.contents);
+            index.chunks.extend(record.chunks);
+        }
+
+        Ok(index)
+    }
+
+    fn is_empty(& self) -> bool { self.contents.is_empty() && self.chunks.is_empty() }
+}
+
+//Fixed size block at the very start of the archive
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Superblock {
+    magic: [u8; 8],
+    major: u16,
+    minor: u16,
+    required_flags: u32, //Features a reader must understand to read the archive
+    optional_flags: u32, //Features a reader may safely ignore
+    directory_offset: u64,
+    checksum: u32, //CRC32 of all the preceding fields
+}
+
+//Size of the serialized superblock, the first version directory is written straight after it
+const SUPERBLOCK_SIZE: u64 = 32;
+
+impl Superblock {
+    fn new(directory_offset: u64) -> Self {
+        Superblock {
+            magic: MAGIC,
+            major: FORMAT_MAJOR,
+            minor: FORMAT_MINOR,
+            required_flags: 0,
+            optional_flags: 0,
+            directory_offset,
+            checksum: 0,
+        }
+    }
+
+    //Read and validate the superblock at the start of the archive. A superblock with a bad
+    //checksum is reported as `Damaged` so the caller can attempt recovery
+    fn read(fp: & mut File, archive_path: & Path) -> Result<Self> {
+        fp.seek(SeekFrom::Start(0)).map_err(ArchiveError::io(archive_path, Some(0)))?;
+
+        let mut bytes = [0u8; SUPERBLOCK_SIZE as usize];
+
+        //Too short to hold a superblock, so it can't be an archive
+        fp.read_exact(& mut bytes).map_err(|error| match error.kind() {
+            std::io::ErrorKind::UnexpectedEof => ArchiveError::NotAnArchive(PathBuf::from(archive_path)),
+            _ => ArchiveError::io(archive_path, Some(0))(error),
+        })?;
+
+        if bytes[..MAGIC.len()] != MAGIC {
+            return Err(ArchiveError::NotAnArchive(PathBuf::from(archive_path)));
+        }
+
+        //Other major versions may lay the superblock out differently, so the version is checked before
+        //the checksum. Otherwise their superblocks would look damaged and be replaced during recovery
+        let major = u16::from_le_bytes([bytes[MAGIC.len()], bytes[MAGIC.len() + 1]]);
+
+        if major != FORMAT_MAJOR {
+            let minor = u16::from_le_bytes([bytes[MAGIC.len() + 2], bytes[MAGIC.len() + 3]]);
+
+            return Err(ArchiveError::UnsupportedVersion { path: PathBuf::from(archive_path), major, minor });
+        }
+
+        let superblock = bincode::deserialize::<Superblock>(&bytes).map_err(ArchiveError::decode(archive_path, 0))?;
+
+        if superblock.checksum != crc32fast::hash(&bytes[..SUPERBLOCK_SIZE as usize - 4]) {
+            return Err(ArchiveError::damaged(archive_path, 0));
+        }
+
+        let unknown = superblock.required_flags & !KNOWN_REQUIRED_FLAGS;
+
+        if unknown != 0 {
+            return Err(ArchiveError::UnsupportedFeatures { path: PathBuf::from(archive_path), flags: unknown });
         }
+
+        Ok(superblock)
+    }
+
+    //Write the superblock and flush it to disk. This is the commit point of every append
+    fn write(& mut self, fp: & mut File, archive_path: & Path) -> Result<()> {
+        let mut bytes = bincode::serialize(self).map_err(ArchiveError::encode(archive_path, 0))?;
+
+        self.checksum = crc32fast::hash(&bytes[..SUPERBLOCK_SIZE as usize - 4]);
+        bytes[SUPERBLOCK_SIZE as usize - 4..].copy_from_slice(&self.checksum.to_le_bytes());
+
+        fp.seek(SeekFrom::Start(0)).map_err(ArchiveError::io(archive_path, Some(0)))?;
+        fp.write_all(&bytes).map_err(ArchiveError::io(archive_path, Some(0)))?;
+        fp.sync_all().map_err(ArchiveError::io(archive_path, Some(0)))
+    }
+}
+
+//Write `value` at the current position as a length, a CRC32 of the serialized bytes, then the bytes
+fn write_record<T: Serialize>(fp: & mut File, archive_path: & Path, value: & T) -> Result<()> {
+    let offset = fp.stream_position().map_err(ArchiveError::io(archive_path, None))?;
+
+    let bytes = bincode::serialize(value).map_err(ArchiveError::encode(archive_path, offset))?;
+
+    bincode::serialize_into(&*fp, &(bytes.len() as u64, crc32fast::hash(&bytes))).map_err(ArchiveError::encode(archive_path, offset))?;
+    fp.write_all(&bytes).map_err(ArchiveError::io(archive_path, Some(offset)))
+}
+
+//Read a record written by `write_record` at the current position, checking its length and checksum
+fn read_record<T: DeserializeOwned>(fp: & mut File, archive_path: & Path) -> Result<T> {
+    let offset = fp.stream_position().map_err(ArchiveError::io(archive_path, None))?;
+
+    let (length, checksum) = bincode::deserialize_from::<_, (u64, u32)>(&*fp).map_err(ArchiveError::decode(archive_path, offset))?;
+
+    let archive_length = fp.metadata().map_err(ArchiveError::io(archive_path, None))?.len();
+
+    //Don't trust a damaged length enough to allocate it
+    if length > archive_length.saturating_sub(offset) {
+        return Err(ArchiveError::Corrupt { path: PathBuf::from(archive_path), offset, reason: format!("record length {} runs past the end of the archive", length) });
+    }
+
+    let mut bytes = vec![0u8; length as usize];
+    fp.read_exact(& mut bytes).map_err(ArchiveError::io(archive_path, Some(offset)))?;
+
+    if crc32fast::hash(&bytes) != checksum {
+        return Err(ArchiveError::damaged(archive_path, offset));
     }
+
+    bincode::deserialize(&bytes).map_err(ArchiveError::decode(archive_path, offset))
+}
+
+//A version in the version directory, giving the offset of its header
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+struct DirectoryEntry {
+    number: VersionNumber,
+    offset: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct VersionDirectory {
-    directory: Vec<u64>,
+    directory: Vec<DirectoryEntry>, //Oldest first, so sorted by number
+    contents: Option<u64>, //Offset of the content index, once anything has been stored
 }
 
 impl VersionDirectory {
     pub fn new() -> Self {
         VersionDirectory {
             directory: Vec::new(),
+            contents: None,
+        }
+    }
+
+    pub fn directory(& self) -> & [DirectoryEntry] { self.directory.as_ref() }
+
+    pub fn add(& mut self, number: VersionNumber, offset: u64) { self.directory.push(DirectoryEntry { number, offset }); }
+
+    //Get the offset of the header of version `number`
+    pub fn find(& self, number: VersionNumber) -> Option<u64> {
+        self.directory.binary_search_by_key(&number, |entry| entry.number)
+            .ok()
+            .map(|index| self.directory[index].offset)
+    }
+
+    //Read the directory at `offset`
+    fn read(fp: & mut File, archive_path: & Path, offset: u64) -> Result<Self> {
+        fp.seek(SeekFrom::Start(offset)).map_err(ArchiveError::io(archive_path, Some(offset)))?;
+
+        let mut magic = [0u8; 8];
+        fp.read_exact(& mut magic).map_err(ArchiveError::io(archive_path, Some(offset)))?;
+
+        if magic != DIRECTORY_MAGIC {
+            return Err(ArchiveError::Corrupt { path: PathBuf::from(archive_path), offset, reason: String::from("missing version directory marker") });
+        }
+
+        read_record(fp, archive_path)
+    }
+
+    //Write the directory at the current position
+    fn write(& self, fp: & mut File, archive_path: & Path) -> Result<()> {
+        fp.write_all(&DIRECTORY_MAGIC).map_err(ArchiveError::io(archive_path, None))?;
+
+        write_record(fp, archive_path, self)
+    }
+
+    //Find the committed version directory, falling back to the last intact one in the archive if
+    //the superblock or the directory it points to is damaged
+    fn load(fp: & mut File, archive_path: & Path) -> Result<(Superblock, Self)> {
+
+        let superblock = match Superblock::read(fp, archive_path) {
+            Ok(superblock) => {
+                if let Ok(directory) = VersionDirectory::read(fp, archive_path, superblock.directory_offset) {
+                    return Ok((superblock, directory));
+                }
+
+                Some(superblock)
+            }
+            //The magic number matched but the rest is damaged, so start again from a fresh superblock
+            Err(ArchiveError::Damaged { .. }) => None,
+            Err(error) => return Err(error),
+        };
+
+        let (directory_offset, directory) = VersionDirectory::recover(fp, archive_path)?;
+
+        warn!("archive '{}' has a damaged superblock or version directory, recovered the directory at offset {}", archive_path.display(), directory_offset);
+
+        let superblock = match superblock {
+            Some(superblock) => Superblock { directory_offset, ..superblock },
+            //The flags of the damaged superblock can't be trusted, so work them out from the entries
+            None => Superblock { required_flags: directory.required_flags(fp, archive_path), ..Superblock::new(directory_offset) },
+        };
+
+        Ok((superblock, directory))
+    }
+
+    //The required flags for every entry of every version, and for the headers duplicates refer to.
+    //If anything can't be read every known flag is returned, so no feature in use is ever dropped
+    fn required_flags(& self, fp: & mut File, archive_path: & Path) -> u32 {
+        let mut flags = 0;
+        let mut seen = std::collections::HashSet::new();
+
+        for entry in self.directory.iter() {
+            let header = match read_version_header(fp, archive_path, entry.offset) {
+                Ok(header) => header,
+                Err(_) => return KNOWN_REQUIRED_FLAGS,
+            };
+
+            for file in header.files.iter() {
+                let mut offset = Some(file.offset);
+
+                while let Some(current) = offset.filter(|offset| seen.insert(*offset)) {
+                    let contents = match read_file_header(fp, archive_path, current) {
+                        Ok(header) => header.contents,
+                        Err(_) => return KNOWN_REQUIRED_FLAGS,
+                    };
+
+                    offset = None;
+
+                    flags |= match contents {
+                        Contents::Snapshot | Contents::Patch { .. } => 0,
+                        Contents::Empty => REQUIRED_EMPTY_CONTENTS,
+                        Contents::Link { .. } => REQUIRED_LINK_CONTENTS,
+                        Contents::Chunked { .. } => REQUIRED_CHUNKED_CONTENTS,
+                        Contents::Deleted => REQUIRED_DELETED_CONTENTS,
+                        Contents::Duplicate { target } => {
+                            offset = Some(target);
+                            REQUIRED_DUPLICATE_CONTENTS
+                        }
+                    };
+                }
+            }
         }
+
+        flags
+    }
+
+    //Check that a directory found by scanning belongs to this archive. The marker can also turn up
+    //inside a stored payload, such as an archive stored uncompressed, followed by a valid record, so
+    //every version it lists must come before it, in order, and read back
+    fn plausible(& self, fp: & mut File, archive_path: & Path, offset: u64) -> bool {
+        let before = |position: u64| position >= SUPERBLOCK_SIZE && position < offset;
+
+        let ordered = self.directory.windows(2).all(|pair| pair[0].number < pair[1].number && pair[0].offset < pair[1].offset);
+
+        ordered
+            && self.contents.is_none_or(before)
+            && self.directory.iter().all(|entry| {
+                before(entry.offset) && read_version_header(fp, archive_path, entry.offset).is_ok_and(|header| header.number == entry.number)
+            })
     }
 
-    pub fn directory(& self) -> & [u64] { self.directory.as_ref() }
+    //Scan backwards from the end of the archive for the last directory that reads back intact
+    fn recover(fp: & mut File, archive_path: & Path) -> Result<(u64, Self)> {
+        const WINDOW: u64 = 64 * 1024;
+
+        let length = fp.metadata().map_err(ArchiveError::io(archive_path, None))?.len();
+        let overlap = DIRECTORY_MAGIC.len() as u64 - 1;
+
+        let mut end = length;
+
+        while end > SUPERBLOCK_SIZE {
+            let start = end.saturating_sub(WINDOW).max(SUPERBLOCK_SIZE);
 
-    pub fn add(& mut self, offset: u64) { self.directory.push(offset); }
+            //Read a little past the end of the window so markers straddling windows are still found
+            let mut buffer = vec![0u8; ((end + overlap).min(length) - start) as usize];
+            fp.seek(SeekFrom::Start(start)).map_err(ArchiveError::io(archive_path, Some(start)))?;
+            fp.read_exact(& mut buffer).map_err(ArchiveError::io(archive_path, Some(start)))?;
+
+            let candidates = buffer.windows(DIRECTORY_MAGIC.len())
+                .enumerate()
+                .filter(|(index, window)| start + (*index as u64) < end && *window == DIRECTORY_MAGIC)
+                .map(|(index, _)| start + index as u64)
+                .collect::<Vec<_>>();
+
+            for offset in candidates.into_iter().rev() {
+                if let Ok(directory) = VersionDirectory::read(fp, archive_path, offset) {
+                    if directory.plausible(fp, archive_path, offset) {
+                        return Ok((offset, directory));
+                    }
+                }
+            }
+
+            end = start;
+        }
+
+        Err(ArchiveError::Corrupt { path: PathBuf::from(archive_path), offset: SUPERBLOCK_SIZE, reason: String::from("no intact version directory found") })
+    }
 }
 
 impl Metadata {
-    fn new(path: & Path) -> Self {
+    //Metadata for a writable regular file with no times, for contents that don't come from disk
+    pub fn file() -> Self {
+        Metadata {
+            file_type: FileType::File,
+            len: 0,
+            read_only: false,
+            modified: None,
+            accessed: None,
+            created: None,
+        }
+    }
 
-        let metadata = std::fs::metadata(path).unwrap();
+    //Read the metadata of the file, directory or link at `path`, without following links
+    pub fn new(path: & Path) -> Result<Self> {
 
-        Metadata {
-            file_type: if metadata.is_file() { FileType::File } else { FileType::Directory },
-            len: metadata.len(),
+        //Links are archived as links, not as whatever they point to
+        let metadata = std::fs::symlink_metadata(path).map_err(ArchiveError::io(path, None))?;
+
+        let file_type = if metadata.file_type().is_symlink() {
+            FileType::SystemLink
+        } else if metadata.is_dir() {
+            FileType::Directory
+        } else {
+            FileType::File
+        };
+
+        Ok(Metadata {
+            file_type,
+            //Directories and links have no contents of their own in the archive
+            len: if metadata.is_file() { metadata.len() } else { 0 },
             read_only: metadata.permissions().readonly(),
-            modified: if let std::io::Result::Ok(date_time) = metadata.modified() { Some(date_time) } else { None },
-            accessed: if let std::io::Result::Ok(date_time) = metadata.accessed() { Some(date_time) } else { None },
-            created: if let std::io::Result::Ok(date_time) = metadata.created() { Some(date_time) } else { None },
-        }
+            modified: metadata.modified().ok(),
+            accessed: metadata.accessed().ok(),
+            created: metadata.created().ok(),
+        })
     }
 }
 
 impl FileHeader {
-    fn new(path: & Path, contents: Contents) -> Self {
-        let metadata = Metadata::new(path);
-        let path = PathBuf::from(path);
-
+    //Create a header for an entry with `metadata`, to be stored in the archive as `path`
+    fn new(metadata: Metadata, path: & Path, contents: Contents) -> Self {
         FileHeader {
             compressed_size: 0,
+            compression: Compression::Stored,
+            hash: [0; 32],
             metadata,
-            path,
+            path: PathBuf::from(path),
             contents
         }
+    }
+}
+
+//Summary of a version, as listed by `ReadArchive::versions`
+#[derive(Debug, Clone)]
+pub struct VersionInfo<'a> {
+    pub number: VersionNumber,
+    pub message: &'a str,
+    pub entries: usize,
+}
+
+//A single entry of a version, as listed by `ReadArchive::entries`
+#[derive(Debug, Clone)]
+pub struct EntryInfo<'a> {
+    pub path: &'a Path,
+    pub file_type: FileType,
+    pub len: u64, //Uncompressed length, 0 for directories and links
+    pub compressed_size: u64, //Bytes stored in the archive for this entry, for patches only the delta, for chunks all of them, 0 for duplicates
+    pub compression: Compression, //Stored for entries without a payload of their own, chunks record theirs
+    pub chunks: usize, //Number of chunks the contents are split into, 0 unless chunked
+    pub link_target: Option<&'a Path>,
+    pub deleted: bool, //The path was removed in this version, `modified` is when
+    pub modified: Option<SystemTime>,
+    pub accessed: Option<SystemTime>,
+    pub created: Option<SystemTime>,
+}
 
+impl<'a> EntryInfo<'a> {
+    fn new(entry: &'a IndexEntry, header: &'a FileHeader) -> Self {
+        EntryInfo {
+            path: &entry.path,
+            file_type: header.metadata.file_type,
+            len: header.metadata.len,
+            compressed_size: entry.compressed_size,
+            compression: header.compression,
+            chunks: match & header.contents {
+                Contents::Chunked { chunks } => chunks.len(),
+                _ => 0,
+            },
+            link_target: match & header.contents {
+                Contents::Link { target } => Some(target.as_path()),
+                _ => None,
+            },
+            deleted: matches!(header.contents, Contents::Deleted),
+            modified: header.metadata.modified,
+            accessed: header.metadata.accessed,
+            created: header.metadata.created,
+        }
     }
 }
 
+//A single entry of a version, as listed by `ReadArchive::paths` from the path table alone
+#[derive(Debug, Clone)]
+pub struct PathInfo<'a> {
+    pub path: &'a Path,
+    pub len: u64, //Uncompressed length, 0 for directories and links
+    pub compressed_size: u64, //Bytes stored in the archive for this entry, as for `EntryInfo`
+    pub deleted: bool, //The path was removed in this version
+}
+
 pub struct Archive {
     path: PathBuf,
 }
@@ -143,213 +655,1622 @@ impl Archive {
         }
     }
 
-    pub fn create(& self) {
+    pub fn create(& self) -> Result<()> {
         let mut fp = OpenOptions::new()
             .write(true)
             .create(true)
             .truncate(true)
-            .open(&self.path).unwrap();
+            .open(&self.path).map_err(ArchiveError::io(&self.path, None))?;
 
-        //insert a 8u64 at the beginning
-        bincode::serialize_into(&fp, &8u64);
+        //insert the superblock at the beginning
+        Superblock::new(SUPERBLOCK_SIZE).write(& mut fp, &self.path)?;
 
         //Insert an empty VersionDirectory after
-        bincode::serialize_into(&fp, &VersionDirectory::new());
+        fp.seek(SeekFrom::Start(SUPERBLOCK_SIZE)).map_err(ArchiveError::io(&self.path, Some(SUPERBLOCK_SIZE)))?;
+        VersionDirectory::new().write(& mut fp, &self.path)?;
+        fp.sync_all().map_err(ArchiveError::io(&self.path, None))?;
+
+        Ok(())
+    }
+
+    //Start appending a version. `number` must be greater than the number of every version already in the archive
+    pub fn appender(& mut self, number: VersionNumber, message: String) -> Result<AppendArchive> {
+        AppendArchive::new(&self.path, Some(number), message)
     }
 
-    pub fn appender(& mut self, number: VersionNumber, message: String) -> AppendArchive {
-        AppendArchive::new(&self.path, number, message)
+    //Start appending a version numbered one after the latest, or 1 if the archive is empty
+    pub fn appender_next(& mut self, message: String) -> Result<AppendArchive> {
+        AppendArchive::new(&self.path, None, message)
     }
 
-    pub fn reader(& mut self) -> ReadArchive {
+    pub fn reader(& mut self) -> Result<ReadArchive> {
         ReadArchive::new(&self.path)
     }
+
+    //Check the whole archive without opening a reader, so archives too damaged to open can still be examined
+    pub fn verify(& self) -> Result<VerifyReport> {
+        let mut fp = OpenOptions::new()
+            .read(true)
+            .open(&self.path).map_err(ArchiveError::io(&self.path, None))?;
+
+        verify::verify(& mut fp, &self.path)
+    }
 }
 
-pub struct AppendArchive {
-    fp: File,
-    backup_directory: VersionDirectory, //A backup of the version directory
-    version_header: VersionHeader,
+//Passes reads through while hashing everything read
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    length: u64, //Bytes read so far
 }
 
-impl AppendArchive {
-    //Open file
-    fn new(archive_path: & Path, number: VersionNumber, message: String) -> Self {
-        let mut fp = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .create(true)
-            .open(archive_path).unwrap();
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        HashingReader { inner, hasher: Sha256::new(), length: 0 }
+    }
 
-        //Get the first u64 (version directory offset)
-        let offset = bincode::deserialize_from::<_, u64>(&fp).unwrap();
+    fn finish(self) -> [u8; 32] { self.hasher.finalize().into() }
+}
 
-        //seek to this offset
-        fp.seek(SeekFrom::Start(offset)).unwrap();
+impl<R: Read> Read for HashingReader<R> {
+    fn read(& mut self, buf: & mut [u8]) -> std::io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.hasher.update(&buf[..count]);
+        self.length += count as u64;
+        Ok(count)
+    }
+}
 
-        //make a backup of the data from offset to EOF
-        let backup_directory = bincode::deserialize_from::<_, VersionDirectory>(&fp).unwrap();
+//Hash and measure everything in `fp`, then go back to its start
+fn hash_file(fp: & mut File, source: & Path) -> Result<(u64, [u8; 32])> {
+    let mut hashing = HashingReader::new(&*fp);
+    std::io::copy(& mut hashing, & mut std::io::sink()).map_err(ArchiveError::io(source, None))?;
 
-        //Seek back to offset, so that future appends overwite the old version directory
-        fp.seek(SeekFrom::Start(offset)).unwrap();
+    let len = hashing.length;
+    let hash = hashing.finish();
 
-        let version_header = VersionHeader::new(number, message);
+    fp.rewind().map_err(ArchiveError::io(source, None))?;
 
-        AppendArchive {
-            fp,
-            backup_directory,
-            version_header,
-        }
+    Ok((len, hash))
+}
 
+//Passes writes through while hashing everything written
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        HashingWriter { inner, hasher: Sha256::new() }
     }
 
-    //Append Version to archive, sort out directory and the directory offset
-    pub fn append_snapshot<P: AsRef<Path>>(& mut self, path: P) {
+    fn finish(self) -> [u8; 32] { self.hasher.finalize().into() }
+}
 
-        if path.as_ref().is_absolute() {
-            panic!("Appended path MUST be relative.")
-        }
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(& mut self, buf: & [u8]) -> std::io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.hasher.update(&buf[..count]);
+        Ok(count)
+    }
 
-        //Save the position of the header
-        let position = self.fp.stream_position().unwrap();
+    fn flush(& mut self) -> std::io::Result<()> { self.inner.flush() }
+}
 
-        //Create the file header for the file entry
-        let header = FileHeader::new(path.as_ref(), Contents::Snapshot);
+//Read the file header at `offset`, leaving the file positioned at the start of its payload
+fn read_file_header(fp: & mut File, archive_path: & Path, offset: u64) -> Result<FileHeader> {
+    fp.seek(SeekFrom::Start(offset)).map_err(ArchiveError::io(archive_path, Some(offset)))?;
 
-        //Write the header to the archive
-        bincode::serialize_into(&self.fp, &header).unwrap();
+    read_record(fp, archive_path)
+}
 
-        //Open the file to append
-        let mut fp = OpenOptions::new()
-            .read(true)
-            .open(path.as_ref()).unwrap();
+//Read the version header at `offset`
+fn read_version_header(fp: & mut File, archive_path: & Path, offset: u64) -> Result<VersionHeader> {
+    fp.seek(SeekFrom::Start(offset)).map_err(ArchiveError::io(archive_path, Some(offset)))?;
 
-        //Copy the file into the archive and compress it
-        //    Move the compressed data and get the size of the data moved
-        let start = self.fp.stream_position().unwrap();
-        lzma_compress(& mut std::io::BufReader::new(&fp), & mut self.fp).unwrap();
-        let compressed_size = self.fp.stream_position().unwrap() - start;
+    read_record(fp, archive_path)
+}
 
-        //    Make a copy of the current seek position
-        let save = self.fp.stream_position().unwrap();
+//Turn a relative path into the form stored in the archive, dropping `.` components and separating
+//the rest with `/` on every platform, so archives extract the same everywhere. Absolute paths and
+//paths that climb out with `..` are refused
+fn normalise(path: & Path) -> Result<PathBuf> {
+    let mut normalised = std::ffi::OsString::new();
 
-        //    Go back and manually add the 'compressed_size' entry to the file header
-        self.fp.seek(SeekFrom::Start(position));
-        bincode::serialize_into(&self.fp, &compressed_size).unwrap();
+    for component in path.components() {
+        match component {
+            std::path::Component::Normal(name) => {
+                if !normalised.is_empty() {
+                    normalised.push("/");
+                }
 
-        //    Seek back to the saved position
-        self.fp.seek(SeekFrom::Start(save)).unwrap();
+                normalised.push(name);
+            }
+            std::path::Component::CurDir => {}
+            _ => return Err(ArchiveError::InvalidPath(PathBuf::from(path))),
+        }
+    }
 
-        //Add position of the header to list
-        self.version_header.insert(path.as_ref(), position);
+    Ok(PathBuf::from(normalised))
+}
 
-    }
+//Check whether `path` is a symbolic link, without following it
+fn is_link(path: & Path) -> Result<bool> {
+    Ok(std::fs::symlink_metadata(path).map_err(ArchiveError::io(path, None))?.file_type().is_symlink())
+}
 
-    pub fn finish(& mut self) {
+//Check that `relative` exists below `root` without going through a link or anything else that
+//isn't a directory, so it is what walking the tree from `root` would find
+fn in_tree(root: & Path, relative: & Path) -> bool {
+    let mut path = PathBuf::from(root);
+    let mut components = relative.components().peekable();
 
-        let version_header_offset = self.fp.stream_position().unwrap();
+    while let Some(component) = components.next() {
+        path.push(component);
 
-        //Append the version header
-        bincode::serialize_into(&self.fp, &self.version_header).unwrap();
+        let metadata = match std::fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => return false,
+        };
 
-        //Get the size of the file (offset version directory)
-        let directory_offset = self.fp.stream_len().unwrap();
+        if components.peek().is_some() && !metadata.is_dir() {
+            return false;
+        }
+    }
 
-        //Add the new entry in the version directory
-        self.backup_directory.add(version_header_offset);
+    true
+}
 
-        //append the new version directory
-        bincode::serialize_into(&self.fp, &self.backup_directory).unwrap();
+//Find a symbolic link below `root` on the way to `relative`, or at `relative` itself, that writing
+//there would follow
+fn link_in_path(root: & Path, relative: & Path) -> Option<PathBuf> {
+    let mut path = PathBuf::from(root);
 
-        //set the first u64 to the offset of the version directory
-        self.fp.seek(SeekFrom::Start(0)).unwrap();
+    for component in relative.components() {
+        path.push(component);
 
-        bincode::serialize_into(&self.fp, &directory_offset);
+        if std::fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            return Some(path);
+        }
     }
 
+    None
 }
 
-/*impl Drop for AppendArchive {
-    fn drop(&mut self) {
-        self.finish();
-    }
-}*/
+//Create a symbolic link at `path` pointing to `target`
+#[cfg(unix)]
+fn create_link(target: & Path, path: & Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
 
-pub struct ReadArchive {
-    fp: File,
-    version_headers: Vec<Version>,
+//Create a symbolic link at `path` pointing to `target`. Windows needs to know whether the target is
+//a directory, so links whose target doesn't exist (yet) are created as file links
+#[cfg(windows)]
+fn create_link(target: & Path, path: & Path) -> std::io::Result<()> {
+    let resolved = path.parent().map_or_else(|| PathBuf::from(target), |parent| parent.join(target));
+
+    if resolved.is_dir() {
+        std::os::windows::fs::symlink_dir(target, path)
+    } else {
+        std::os::windows::fs::symlink_file(target, path)
+    }
 }
 
-impl ReadArchive {
-    fn new(archive_path: & Path) -> Self {
+//Apply the stored access and modification times and read-only flag to the restored entry at
+//`path`. Links get their own times set, and keep their permissions since those are meaningless
+fn apply_metadata(metadata: & Metadata, path: & Path) -> Result<()> {
 
-        let mut fp = OpenOptions::new()
-            .read(true)
-            .open(archive_path).unwrap();
+    let accessed = metadata.accessed.map(filetime::FileTime::from_system_time);
+    let modified = metadata.modified.map(filetime::FileTime::from_system_time);
 
-        //Get the first u64 (version directory offset)
-        let version_directory_offset = bincode::deserialize_from::<_, u64>(&fp).unwrap();
+    let link = matches!(metadata.file_type, FileType::SystemLink);
 
-        //Seek to directory
-        fp.seek(SeekFrom::Start(version_directory_offset));
+    match (accessed, modified) {
+        (Some(accessed), Some(modified)) if link => filetime::set_symlink_file_times(path, accessed, modified),
+        (Some(accessed), Some(modified)) => filetime::set_file_times(path, accessed, modified),
+        (None, Some(modified)) if !link => filetime::set_file_mtime(path, modified),
+        (Some(accessed), None) if !link => filetime::set_file_atime(path, accessed),
+        _ => Ok(()),
+    }.map_err(ArchiveError::io(path, None))?;
 
-        println!("version_directory_offset: {}", version_directory_offset);
+    if metadata.read_only && !link {
+        let mut permissions = std::fs::metadata(path).map_err(ArchiveError::io(path, None))?.permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(path, permissions).map_err(ArchiveError::io(path, None))?;
+    }
 
-        //Get directory
-        let version_directory = bincode::deserialize_from::<_, VersionDirectory>(&fp).unwrap();
+    Ok(())
+}
 
-        println!("directory: {:?}", version_directory);
+//Check that a duplicate or patch at `offset` refers back to an earlier header, so chains always end
+fn earlier_header(archive_path: & Path, offset: u64, target: u64) -> Result<u64> {
+    if target >= offset {
+        return Err(ArchiveError::Corrupt { path: PathBuf::from(archive_path), offset, reason: format!("header refers forwards to offset {}", target) });
+    }
 
-        let mut version_headers = Vec::new();
+    Ok(target)
+}
 
+//Rebuild the full contents of the file whose header lives at `offset`, replaying any patch chain
+//back to the nearest snapshot
+fn reconstruct(fp: & mut File, archive_path: & Path, offset: u64) -> Result<Vec<u8>> {
 
-        for offset in version_directory.directory.iter() {
-            let mut file_header_map = HashMap::new();
+    //Walk the chain from the requested header back to its snapshot, collecting the payloads on the way
+    let mut payloads = Vec::new();
+    let requested = offset;
+    let mut offset = offset;
+    let mut hash = None;
 
-            fp.seek(SeekFrom::Start(*offset));
+    //Every patch records how many patches away from stored contents it is, and its base is one closer.
+    //Checking that keeps the chain as long as the policy made it, even if the archive is damaged
+    let mut expected_depth = None;
 
-            let header = bincode::deserialize_from::<_, VersionHeader>(&fp).unwrap(); //bincode::deserialize::<VersionHeader>(&buffer).unwrap();
+    loop {
+        let header = read_file_header(fp, archive_path, offset)?;
 
-            for (file_path, file_header_offset) in header.files.iter() {
-                fp.seek(SeekFrom::Start(*file_header_offset));
+        hash.get_or_insert(header.hash);
 
-                let file_head = bincode::deserialize_from::<_, FileHeader>(&fp).unwrap();
+        let mut payload = Vec::new();
 
-                file_header_map.insert(file_path.clone(), (fp.stream_position().unwrap(), file_head));
+        let depth = match header.contents {
+            Contents::Patch { depth: 0, .. } => return Err(ArchiveError::Corrupt { path: PathBuf::from(archive_path), offset, reason: "patch has a depth of 0".to_string() }),
+            Contents::Patch { depth, .. } => depth,
+            //Duplicates only ever refer to stored contents, so one can only start a chain
+            Contents::Duplicate { .. } if offset != requested => return Err(ArchiveError::Corrupt { path: PathBuf::from(archive_path), offset, reason: "chain continues from a duplicate".to_string() }),
+            _ => 0,
+        };
 
-            }
+        if let Some(expected) = expected_depth.filter(|expected| *expected != depth) {
+            return Err(ArchiveError::Corrupt { path: PathBuf::from(archive_path), offset, reason: format!("patch base has depth {} where {} was expected", depth, expected) });
+        }
 
-            let version = Version::new(file_header_map, header.number, header.message.clone());
+        if let Contents::Empty | Contents::Link { .. } | Contents::Deleted = header.contents {
+            payloads.push((offset, payload));
+            break;
+        }
 
-            println!("Version: {:?}", version);
+        if let Contents::Duplicate { target } = header.contents {
+            offset = earlier_header(archive_path, offset, target)?;
+            continue;
+        }
 
-            version_headers.push(version);
+        if let Contents::Chunked { chunks } = &header.contents {
+            for chunk in chunks.iter() {
+                payload.extend(read_chunk(fp, archive_path, *chunk)?);
+            }
 
+            payloads.push((offset, payload));
+            break;
         }
 
-        ReadArchive {
-            fp,
-            version_headers,
+        let mut taken = std::io::Read::by_ref(fp).take(header.compressed_size);
+        header.compression.check()?.decompress(& mut std::io::BufReader::new(& mut taken), & mut payload).map_err(ArchiveError::decompression(archive_path, offset))?;
+
+        payloads.push((offset, payload));
+
+        match header.contents {
+            Contents::Patch { base, .. } => {
+                offset = earlier_header(archive_path, offset, base)?;
+                expected_depth = Some(depth - 1);
+            }
+            _ => break,
         }
     }
 
-    pub fn file<W: Write, P: AsRef<Path>>(& mut self, version: usize, path: P, mut writer: & mut W) -> Option<()> {
+    //The last payload is the snapshot (or chunks, or empty), apply the patches on top of it from oldest to newest
+    let (_, mut contents) = payloads.pop().unwrap();
+
+    while let Some((offset, delta)) = payloads.pop() {
+        let mut patched = Vec::new();
+        bsdiff::patch(&contents, & mut delta.as_slice(), & mut patched).map_err(|_| ArchiveError::damaged(archive_path, offset))?;
+        contents = patched;
+    }
+
+    if hash != Some(Sha256::digest(&contents).into()) {
+        return Err(ArchiveError::damaged(archive_path, requested));
+    }
+
+    Ok(contents)
+}
+
+pub struct AppendArchive {
+    fp: File,
+    path: PathBuf,
+    superblock: Superblock,
+    backup_directory: VersionDirectory, //A backup of the version directory
+    previous: Option<VersionHeader>, //The most recent version already in the archive
+    version_header: VersionHeader,
+    root: Option<PathBuf>, //Directory appended paths are relative to, the working directory if not set
+    compression: Compression, //Used for every payload appended from now on
+    rules: CompressionRules, //Decide which payloads are stored rather than compressed
+    contents: ContentIndex, //Everything stored so far, including by this version
+    added: ContentIndex, //Only what this version stored first, written as a new record of the index
+    incremental: Option<ChangeDetection>, //Set when unchanged files are kept from the previous version
+}
+
+impl AppendArchive {
+    //Open file
+    fn new(archive_path: & Path, number: Option<VersionNumber>, message: String) -> Result<Self> {
+        let mut fp = OpenOptions::new()
+            .write(true)
+            .read(true)
+            .create(true)
+            .truncate(false)
+            .open(archive_path).map_err(ArchiveError::io(archive_path, None))?;
+
+        //Get the superblock and the committed version directory it points to
+        let (superblock, mut backup_directory) = VersionDirectory::load(& mut fp, archive_path)?;
+
+        //Load the latest version header so patches can be made against it
+        let previous = match backup_directory.directory().last() {
+            Some(entry) => Some(read_version_header(& mut fp, archive_path, entry.offset).map_err(|error| error.within(entry.number, None))?),
+            None => None,
+        };
 
+        //Numbers must increase, so each one identifies a single version
+        let latest = previous.as_ref().map(|previous| previous.number);
 
-        let version = self.version_headers.get(version).unwrap();
+        let number = match (number, latest) {
+            (Some(number), Some(latest)) if number <= latest => {
+                return Err(ArchiveError::VersionOrder { path: PathBuf::from(archive_path), number, latest });
+            }
+            (Some(number), _) => number,
+            (None, Some(latest)) => latest.next(),
+            (None, None) => VersionNumber { number: 1 },
+        };
 
-        let (offset, header) = version.files.get(path.as_ref())?;
+        //Load the content index. It only saves space, so a damaged one is ignored and a new chain is started
+        let contents = ContentIndex::load(& mut fp, archive_path, backup_directory.contents).unwrap_or_else(|error| {
+            warn!("ignoring the content index of '{}', identical contents will be stored again: {}", archive_path.display(), error);
+            backup_directory.contents = None;
+            ContentIndex::default()
+        });
 
-        let size = header.compressed_size;
+        //Seek to the end, so that future appends never overwrite the committed version directory
+        fp.seek(SeekFrom::End(0)).map_err(ArchiveError::io(archive_path, None))?;
 
-        self.fp.seek(SeekFrom::Start(*offset));
+        let version_header = VersionHeader::new(number, message);
 
-        let mut taken = std::io::Read::by_ref(&mut self.fp).take(size);
+        info!("appending version {} to '{}'", number, archive_path.display());
 
-        lzma_decompress(& mut std::io::BufReader::new(& mut taken), & mut writer).unwrap();
+        Ok(AppendArchive {
+            fp,
+            path: PathBuf::from(archive_path),
+            superblock,
+            backup_directory,
+            previous,
+            version_header,
+            root: None,
+            compression: Compression::default(),
+            rules: CompressionRules::default(),
+            contents,
+            added: ContentIndex::default(),
+            incremental: None,
+        })
 
-        //std::io::copy(& mut taken, & mut writer).unwrap();
+    }
+
+    //Get the current position in the archive
+    fn position(& mut self) -> Result<u64> {
+        self.fp.stream_position().map_err(ArchiveError::io(&self.path, None))
+    }
+
+    //Move to `offset` in the archive
+    fn seek(& mut self, offset: u64) -> Result<()> {
+        self.fp.seek(SeekFrom::Start(offset)).map_err(ArchiveError::io(&self.path, Some(offset)))?;
+        Ok(())
+    }
+
+    //Add the header written at `position` to the version
+    fn record(& mut self, header: & FileHeader, position: u64) {
+        self.record_stored(header, position, header.compressed_size);
+    }
+
+    //Add the header written at `position` to the version, with `compressed_size` bytes stored for it
+    //somewhere in the archive rather than straight after the header
+    fn record_stored(& mut self, header: & FileHeader, position: u64, compressed_size: u64) {
+        debug!("appended '{}' to version {} at offset {} as {:?}, {} bytes stored with {}", header.path.display(), self.version_header.number, position, header.contents, compressed_size, header.compression);
+
+        if let Contents::Snapshot | Contents::Patch { .. } | Contents::Chunked { .. } = header.contents {
+            if let std::collections::hash_map::Entry::Vacant(entry) = self.contents.contents.entry(header.hash) {
+                entry.insert(position);
+                self.added.contents.insert(header.hash, position);
+            }
+        }
+
+        self.version_header.insert(header, position, compressed_size);
+    }
+
+    //Append an entry stored as `name` referring to the earlier header at `target` for its contents
+    fn duplicate(& mut self, metadata: Metadata, name: & Path, target: u64, hash: [u8; 32]) -> Result<()> {
+
+        let position = self.position()?;
+
+        let mut header = FileHeader::new(metadata, name, Contents::Duplicate { target });
+        header.hash = hash;
+
+        write_record(& mut self.fp, &self.path, &header)?;
+
+        self.superblock.required_flags |= REQUIRED_DUPLICATE_CONTENTS;
+
+        self.record(&header, position);
+
+        Ok(())
+    }
+
+    //Append `source` as a duplicate stored as `name` if contents of length `len` with `hash` are
+    //already in the archive
+    fn deduplicate(& mut self, source: & Path, name: & Path, len: u64, hash: [u8; 32]) -> Result<bool> {
+
+        let target = match self.contents.contents.get(&hash) {
+            Some(target) => *target,
+            None => return Ok(false),
+        };
+
+        self.duplicate(Metadata { len, ..Metadata::new(source)? }, name, target, hash)?;
+
+        Ok(true)
+    }
+
+    //Set the policy used by `append` to choose between snapshots and patches. The policy is
+    //recorded in the version header
+    pub fn set_policy(& mut self, policy: PatchPolicy) {
+        self.version_header.policy = policy;
+    }
+
+    //Start this version with every entry of the previous version, so it holds the full state without
+    //appending everything again. Files appended from now on are only stored again if `detection`
+    //finds they have changed, otherwise the entry of the previous version is kept
+    pub fn set_incremental(& mut self, detection: ChangeDetection) {
+
+        if let Some(previous) = & self.previous {
+            for entry in previous.files.iter() {
+                if !entry.deleted && self.version_header.get(&entry.path).is_none() {
+                    self.version_header.insert_entry(entry.clone());
+                }
+            }
+        }
+
+        self.incremental = Some(detection);
+    }
+
+    //In incremental mode, keep the entry of the previous version for `source` if it hasn't changed
+    fn unchanged(& mut self, source: & Path, name: & Path) -> Result<bool> {
+
+        let detection = match self.incremental {
+            Some(detection) => detection,
+            None => return Ok(false),
+        };
+
+        let entry = match self.previous.as_ref().and_then(|previous| previous.get(name)) {
+            Some(entry) if !entry.deleted => entry.clone(),
+            _ => return Ok(false),
+        };
+
+        //Read the previous header, then return to the end of the archive
+        let save = self.position()?;
+        let header = read_file_header(& mut self.fp, &self.path, entry.offset)?;
+        self.seek(save)?;
+
+        let metadata = Metadata::new(source)?;
+
+        let mut unchanged = header.metadata.file_type == metadata.file_type
+            && header.metadata.len == metadata.len
+            && header.metadata.modified == metadata.modified
+            && header.metadata.read_only == metadata.read_only;
+
+        if let Contents::Link { target } = &header.contents {
+            unchanged = unchanged && std::fs::read_link(source).map_err(ArchiveError::io(source, None))? == *target;
+        }
+
+        if unchanged && detection == ChangeDetection::Hash && metadata.file_type == FileType::File {
+            let mut fp = File::open(source).map_err(ArchiveError::io(source, None))?;
+
+            unchanged = hash_file(& mut fp, source)? == (header.metadata.len, header.hash);
+        }
+
+        if unchanged {
+            debug!("'{}' is unchanged, keeping the entry at offset {}", name.display(), entry.offset);
+
+            self.version_header.insert_entry(entry);
+        }
+
+        Ok(unchanged)
+    }
+
+    //Set the compression used for payloads appended from now on. Each entry records its own
+    //compression, so a version can mix them
+    pub fn set_compression(& mut self, compression: Compression) -> Result<()> {
+        self.compression = compression.check()?;
+        Ok(())
+    }
+
+    //Set the rules deciding which payloads are stored uncompressed
+    pub fn set_rules(& mut self, rules: CompressionRules) {
+        self.rules = rules;
+    }
+
+    //Compress `payload`, the whole of what will be stored for `name`, as the rules decide
+    fn compress(& self, name: & Path, payload: & [u8]) -> std::io::Result<(Compression, Vec<u8>)> {
+        let compression = self.rules.choose(self.compression, name, payload)?;
+
+        Ok((compression, compression.compress_slice(payload)?))
+    }
+
+    //Read appended paths relative to `root` instead of the working directory. Names in the archive
+    //are the paths relative to the root, and absolute paths must lie inside it
+    pub fn set_root<P: AsRef<Path>>(& mut self, root: P) {
+        self.root = Some(PathBuf::from(root.as_ref()));
+    }
+
+    //Work out where `path` is read from and the name it is stored as
+    fn locate(& self, path: & Path) -> Result<(PathBuf, PathBuf)> {
+
+        let (source, relative) = match & self.root {
+            Some(root) if path.is_absolute() => {
+                let relative = path.strip_prefix(root).map_err(|_| ArchiveError::InvalidPath(PathBuf::from(path)))?;
+                (PathBuf::from(path), relative)
+            }
+            Some(root) => (root.join(path), path),
+            None => (PathBuf::from(path), path),
+        };
+
+        Ok((source, normalise(relative)?))
+    }
+
+    //Like `locate`, for entries that need a name of their own
+    fn locate_file(& self, path: & Path) -> Result<(PathBuf, PathBuf)> {
+
+        let (source, name) = self.locate(path)?;
+
+        if name.as_os_str().is_empty() {
+            return Err(ArchiveError::InvalidPath(PathBuf::from(path)));
+        }
+
+        Ok((source, name))
+    }
+
+    //Append Version to archive, sort out directory and the directory offset
+    pub fn append_snapshot<P: AsRef<Path>>(& mut self, path: P) -> Result<()> {
+
+        let (source, name) = self.locate_file(path.as_ref())?;
+
+        if is_link(&source)? {
+            return self.link(&source, &name);
+        }
+
+        if self.unchanged(&source, &name)? {
+            return Ok(());
+        }
+
+        self.snapshot(&source, &name)
+
+    }
+
+    //Append the file split into content defined chunks. Only chunks not already in the archive are
+    //stored, so a large file changed in a few places costs little more than the changes
+    pub fn append_chunked<P: AsRef<Path>>(& mut self, path: P) -> Result<()> {
+
+        let (source, name) = self.locate_file(path.as_ref())?;
+
+        if is_link(&source)? {
+            return self.link(&source, &name);
+        }
+
+        if self.unchanged(&source, &name)? {
+            return Ok(());
+        }
+
+        self.chunked(&source, &name)
+
+    }
+
+    //Append the file as a binary delta against its contents in the most recent version. If the
+    //file does not exist in that version, a snapshot is stored instead
+    pub fn append_patch<P: AsRef<Path>>(& mut self, path: P) -> Result<()> {
+
+        let (source, name) = self.locate_file(path.as_ref())?;
+
+        if is_link(&source)? {
+            return self.link(&source, &name);
+        }
+
+        if self.unchanged(&source, &name)? {
+            return Ok(());
+        }
+
+        let (base, depth) = match self.base(&name)? {
+            Some(base) => base,
+            None => return self.snapshot(&source, &name),
+        };
+
+        let new = std::fs::read(&source).map_err(ArchiveError::io(&source, None))?;
+
+        if self.deduplicate(&source, &name, new.len() as u64, Sha256::digest(&new).into())? {
+            return Ok(());
+        }
+
+        let delta = self.delta(base, &new)?;
+
+        let (compression, compressed_patch) = self.compress(&name, &delta).map_err(ArchiveError::io(&source, None))?;
+
+        self.append_compressed(&source, &name, Contents::Patch { base, depth: depth + 1 }, &new, compression, &compressed_patch)
+
+    }
+
+    //Append the file as either a snapshot or a patch, whichever the policy prefers
+    pub fn append<P: AsRef<Path>>(& mut self, path: P) -> Result<()> {
+
+        let (source, name) = self.locate_file(path.as_ref())?;
+
+        self.choose(&source, &name)
+
+    }
+
+    //Append the contents read from `reader` as a snapshot stored as `archive_path`, with `metadata`
+    //supplied by the caller rather than read from disk. The entry is always a regular file, and its
+    //length is taken from the number of bytes read
+    pub fn append_reader<P: AsRef<Path>, R: Read>(& mut self, archive_path: P, reader: R, metadata: Metadata) -> Result<()> {
+
+        let name = normalise(archive_path.as_ref())?;
+
+        if name.as_os_str().is_empty() {
+            return Err(ArchiveError::InvalidPath(PathBuf::from(archive_path.as_ref())));
+        }
+
+        self.append_entry(Metadata { file_type: FileType::File, ..metadata }, &name, reader)
+
+    }
+
+    //Record that `archive_path`, and everything below it if it is a directory, has been removed in
+    //this version. Only paths in the version, such as those inherited by an incremental version, can
+    //be removed
+    pub fn remove<P: AsRef<Path>>(& mut self, archive_path: P) -> Result<()> {
+
+        let name = normalise(archive_path.as_ref())?;
+
+        if name.as_os_str().is_empty() {
+            return Err(ArchiveError::InvalidPath(PathBuf::from(archive_path.as_ref())));
+        }
+
+        let removed = self.version_header.files.iter()
+            .filter(|entry| !entry.deleted && entry.path.starts_with(&name))
+            .map(|entry| entry.path.clone())
+            .collect::<Vec<_>>();
+
+        if removed.is_empty() {
+            return Err(ArchiveError::FileNotFound { version: self.version_header.number, path: name });
+        }
+
+        for path in removed {
+            self.tombstone(&path)?;
+        }
+
+        Ok(())
+
+    }
+
+    //Append a tombstone recording that `name` was removed
+    fn tombstone(& mut self, name: & Path) -> Result<()> {
+
+        let position = self.position()?;
+
+        let metadata = Metadata { modified: Some(SystemTime::now()), ..Metadata::file() };
+
+        let mut header = FileHeader::new(metadata, name, Contents::Deleted);
+        header.hash = Sha256::digest([]).into();
+
+        write_record(& mut self.fp, &self.path, &header)?;
+
+        self.superblock.required_flags |= REQUIRED_DELETED_CONTENTS;
+
+        debug!("removed '{}'", name.display());
+
+        self.record(&header, position);
+
+        Ok(())
+
+    }
+
+    //Append the directory `root` and everything below it. Directories are recorded as entries of
+    //their own, so empty directories survive, and files are appended as with `append`. A `root` that
+    //is a symbolic link is recorded as a link, and what it points to is not appended
+    pub fn append_tree<P: AsRef<Path>>(& mut self, root: P) -> Result<()> {
+
+        let (source, name) = self.locate(root.as_ref())?;
+
+        //The root itself is only recorded if it has a name, appending "." just appends its contents
+        let root_link = !name.as_os_str().is_empty() && is_link(&source)?;
+
+        if root_link {
+            self.link(&source, &name)?;
+        } else {
+            if !name.as_os_str().is_empty() {
+                self.directory(&source, &name)?;
+            }
+
+            self.walk(&source, &name)?;
+        }
+
+        //Entries an incremental version inherited from below the root that are gone have been removed,
+        //including those below a directory that has been replaced by a link or a file
+        if self.incremental.is_some() {
+            let missing = self.version_header.files.iter()
+                .filter(|entry| !entry.deleted)
+                .filter(|entry| entry.path.strip_prefix(&name).is_ok_and(|relative| !in_tree(&source, relative) || (root_link && !relative.as_os_str().is_empty())))
+                .map(|entry| entry.path.clone())
+                .collect::<Vec<_>>();
+
+            for path in missing {
+                self.tombstone(&path)?;
+            }
+        }
+
+        Ok(())
+
+    }
+
+    //Append the contents of the directory `source`, storing them under `name`
+    fn walk(& mut self, source: & Path, name: & Path) -> Result<()> {
+
+        let mut entries = std::fs::read_dir(source).map_err(ArchiveError::io(source, None))?
+            .collect::<std::io::Result<Vec<_>>>().map_err(ArchiveError::io(source, None))?;
+
+        //Sort so the same tree always produces the same archive
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let source = entry.path();
+            let name = normalise(&name.join(entry.file_name()))?;
+
+            let file_type = entry.file_type().map_err(ArchiveError::io(&source, None))?;
+
+            if file_type.is_symlink() {
+                self.link(&source, &name)?;
+            } else if file_type.is_dir() {
+                self.directory(&source, &name)?;
+                self.walk(&source, &name)?;
+            } else {
+                self.choose(&source, &name)?;
+            }
+        }
+
+        Ok(())
+
+    }
+
+    //Append `source` as a snapshot stored as `name`
+    fn snapshot(& mut self, source: & Path, name: & Path) -> Result<()> {
+
+        //Open the file to append
+        let mut fp = OpenOptions::new()
+            .read(true)
+            .open(source).map_err(ArchiveError::io(source, None))?;
+
+        //Hash the file first, reading it is much cheaper than compressing it only to throw it away
+        let (len, hash) = hash_file(& mut fp, source)?;
+
+        if self.deduplicate(source, name, len, hash)? {
+            return Ok(());
+        }
+
+        self.append_entry(Metadata::new(source)?, name, fp)
+
+    }
+
+    //Append `source` as `name`, storing either a snapshot or a patch, whichever the policy prefers
+    fn choose(& mut self, source: & Path, name: & Path) -> Result<()> {
+
+        if is_link(source)? {
+            return self.link(source, name);
+        }
+
+        if self.unchanged(source, name)? {
+            return Ok(());
+        }
+
+        let policy = self.version_header.policy;
+
+        let len = std::fs::metadata(source).map_err(ArchiveError::io(source, None))?.len();
+
+        if policy.min_chunked_len != 0 && len >= policy.min_chunked_len {
+            return self.chunked(source, name);
+        }
+
+        //Only consider a patch if there is something to patch against and the chain is not too long
+        let (base, depth) = match self.base(name)? {
+            Some((base, depth)) if depth < policy.max_chain_length => (base, depth),
+            _ => return self.snapshot(source, name),
+        };
+
+        let new = std::fs::read(source).map_err(ArchiveError::io(source, None))?;
+
+        if self.deduplicate(source, name, new.len() as u64, Sha256::digest(&new).into())? {
+            return Ok(());
+        }
+
+        let delta = self.delta(base, &new)?;
+
+        //Compress both candidates and keep the patch only if it is small enough
+        let (snapshot_compression, compressed_snapshot) = self.compress(name, &new).map_err(ArchiveError::io(source, None))?;
+
+        let (patch_compression, compressed_patch) = self.compress(name, &delta).map_err(ArchiveError::io(source, None))?;
+
+        if compressed_patch.len() as u64 * 100 > compressed_snapshot.len() as u64 * policy.max_patch_ratio as u64 {
+            self.append_compressed(source, name, Contents::Snapshot, &new, snapshot_compression, &compressed_snapshot)
+        } else {
+            self.append_compressed(source, name, Contents::Patch { base, depth: depth + 1 }, &new, patch_compression, &compressed_patch)
+        }
+
+    }
+
+    //Append `source` split into chunks stored as `name`, storing only the chunks not yet in the archive
+    fn chunked(& mut self, source: & Path, name: & Path) -> Result<()> {
+
+        let mut fp = OpenOptions::new()
+            .read(true)
+            .open(source).map_err(ArchiveError::io(source, None))?;
+
+        //Hash the file first, so a file stored already in any form doesn't store its chunks again
+        let (len, hash) = hash_file(& mut fp, source)?;
+
+        if self.deduplicate(source, name, len, hash)? {
+            return Ok(());
+        }
+
+        let metadata = Metadata::new(source)?;
+
+        let mut chunker = Chunker::new(HashingReader::new(fp));
+        let mut chunks = Vec::new();
+        let mut compressed_size = 0;
+
+        while let Some(chunk) = chunker.next_chunk().map_err(ArchiveError::io(source, None))? {
+            let (chunk, stored) = self.chunk(name, &chunk)?;
+
+            chunks.push(chunk);
+            compressed_size += stored;
+        }
+
+        //Describe what was actually read, in case the file changed since it was hashed
+        let contents = chunker.into_inner();
+        let metadata = Metadata { len: contents.length, ..metadata };
+        let hash = contents.finish();
+
+        let position = self.position()?;
+
+        let mut header = FileHeader::new(metadata, name, Contents::Chunked { chunks });
+        header.hash = hash;
+
+        write_record(& mut self.fp, &self.path, &header)?;
+
+        self.superblock.required_flags |= REQUIRED_CHUNKED_CONTENTS;
+
+        //The header has no payload of its own, so the path table gives the size of its chunks instead
+        self.record_stored(&header, position, compressed_size);
+
+        Ok(())
+
+    }
+
+    //Store `data`, a chunk of `name`, unless the same chunk is stored already. Returns where the chunk
+    //is and the size of its compressed payload
+    fn chunk(& mut self, name: & Path, data: & [u8]) -> Result<(Chunk, u64)> {
+
+        let hash: [u8; 32] = Sha256::digest(data).into();
+        let len = data.len() as u64;
+
+        if let Some(offset) = self.contents.chunks.get(&hash).copied() {
+            //Peek at the stored chunk's header for its size, then return to the end of the archive
+            let save = self.position()?;
+
+            self.seek(offset)?;
+            let header: ChunkHeader = read_record(& mut self.fp, &self.path)?;
+            self.seek(save)?;
+
+            return Ok((Chunk { offset, len }, header.compressed_size));
+        }
+
+        let position = self.position()?;
+
+        let (compression, compressed) = self.compress(name, data).map_err(ArchiveError::io(&self.path, Some(position)))?;
+
+        let header = ChunkHeader { compressed_size: compressed.len() as u64, compression, len, hash };
+
+        write_record(& mut self.fp, &self.path, &header)?;
+        self.fp.write_all(&compressed).map_err(ArchiveError::io(&self.path, Some(position)))?;
+
+        self.contents.chunks.insert(hash, position);
+        self.added.chunks.insert(hash, position);
+
+        Ok((Chunk { offset: position, len }, header.compressed_size))
+
+    }
+
+    //Append the directory `source` as an entry without a payload stored as `name`
+    fn directory(& mut self, source: & Path, name: & Path) -> Result<()> {
+
+        if self.unchanged(source, name)? {
+            return Ok(());
+        }
+
+        let position = self.position()?;
+
+        let mut header = FileHeader::new(Metadata::new(source)?, name, Contents::Empty);
+        header.hash = Sha256::digest([]).into();
+
+        write_record(& mut self.fp, &self.path, &header)?;
+
+        self.superblock.required_flags |= REQUIRED_EMPTY_CONTENTS;
+
+        self.record(&header, position);
+
+        Ok(())
+
+    }
+
+    //Append the symbolic link `source` as an entry holding its target stored as `name`. The target
+    //is stored exactly as it was read, so relative links stay relative
+    fn link(& mut self, source: & Path, name: & Path) -> Result<()> {
+
+        if self.unchanged(source, name)? {
+            return Ok(());
+        }
+
+        let position = self.position()?;
+
+        let target = std::fs::read_link(source).map_err(ArchiveError::io(source, None))?;
+
+        let mut header = FileHeader::new(Metadata::new(source)?, name, Contents::Link { target });
+        header.hash = Sha256::digest([]).into();
+
+        write_record(& mut self.fp, &self.path, &header)?;
+
+        self.superblock.required_flags |= REQUIRED_LINK_CONTENTS;
+
+        self.record(&header, position);
+
+        Ok(())
+
+    }
+
+    //Get the header offset and patch depth of `path` in the most recent version, if it exists
+    fn base(& mut self, path: & Path) -> Result<Option<(u64, u32)>> {
+
+        let base = match self.previous.as_ref().and_then(|previous| previous.get(path)) {
+            Some(base) if !base.deleted => base.offset,
+            _ => return Ok(None),
+        };
+
+        //Peek at the base header to find out how long its chain is, then return to the end of the archive.
+        //Duplicates are followed, so patches are always made against stored contents
+        let save = self.position()?;
+        let mut base = base;
+        let mut header = read_file_header(& mut self.fp, &self.path, base)?;
+
+        while let Contents::Duplicate { target } = header.contents {
+            base = earlier_header(&self.path, base, target)?;
+            header = read_file_header(& mut self.fp, &self.path, base)?;
+        }
+
+        self.seek(save)?;
+
+        let depth = match header.contents {
+            Contents::Patch { depth, .. } => depth,
+            _ => 0,
+        };
+
+        Ok(Some((base, depth)))
+    }
+
+    //Create a binary delta from the contents of the header at `base` to `new`
+    fn delta(& mut self, base: u64, new: & [u8]) -> Result<Vec<u8>> {
+
+        //Rebuild the old contents, then return to the end of the archive
+        let save = self.position()?;
+        let old = reconstruct(& mut self.fp, &self.path, base)?;
+        self.seek(save)?;
+
+        let mut delta = Vec::new();
+        bsdiff::diff(&old, new, & mut delta).map_err(ArchiveError::compression(&self.path, base))?;
+
+        Ok(delta)
+    }
+
+    //Write a snapshot file header followed by the payload read from `file`, compressing it as it is copied
+    fn append_entry<R: Read>(& mut self, metadata: Metadata, path: & Path, mut file: R) -> Result<()> {
+
+        //Save the position of the header
+        let position = self.position()?;
+
+        //Read a sample from the start of the file to decide whether to compress it, then put it back
+        let mut sample = Vec::new();
+        std::io::Read::by_ref(& mut file).take(self.rules.sample_size() as u64).read_to_end(& mut sample).map_err(ArchiveError::io(path, None))?;
+
+        let compression = self.rules.choose(self.compression, path, &sample).map_err(ArchiveError::io(path, None))?;
+        let file = sample.as_slice().chain(file);
+
+        //Create the file header for the file entry
+        let mut header = FileHeader::new(metadata, path, Contents::Snapshot);
+        header.compression = compression;
+
+        //Write a placeholder header to the archive, it is rewritten once the size and hash are known
+        write_record(& mut self.fp, &self.path, &header)?;
+
+        //Copy the file into the archive and compress it, hashing the uncompressed data on the way
+        //    Move the compressed data and get the size of the data moved
+        let start = self.position()?;
+        let mut payload = std::io::BufReader::new(HashingReader::new(file));
+        compression.compress(& mut payload, & mut self.fp).map_err(ArchiveError::io(&self.path, Some(start)))?;
+        let compressed_size = self.position()? - start;
+
+        //    Make a copy of the current seek position
+        let save = self.position()?;
+
+        //    Go back and rewrite the header with the 'compressed_size', 'len' and 'hash' entries. All
+        //    are fixed size, so the header is exactly as long as the placeholder
+        let payload = payload.into_inner();
+        header.compressed_size = compressed_size;
+        header.metadata.len = payload.length;
+        header.hash = payload.finish();
+
+        //Contents only known once read, such as from `append_reader`, may turn out to be stored
+        //already. Throw away what was just written and refer to the earlier copy instead
+        if let Some(target) = self.contents.contents.get(&header.hash).copied() {
+            self.fp.set_len(position).map_err(ArchiveError::io(&self.path, Some(position)))?;
+            self.seek(position)?;
+
+            return self.duplicate(header.metadata, path, target, header.hash);
+        }
+
+        self.seek(position)?;
+        write_record(& mut self.fp, &self.path, &header)?;
+
+        //    Seek back to the saved position
+        self.seek(save)?;
+
+        //Add position of the header to list
+        self.record(&header, position);
+
+        Ok(())
+
+    }
+
+    //Write a file header followed by a payload that has already been compressed
+    fn append_compressed(& mut self, source: & Path, path: & Path, contents: Contents, uncompressed: & [u8], compression: Compression, compressed: & [u8]) -> Result<()> {
+
+        //Save the position of the header
+        let position = self.position()?;
+
+        let mut header = FileHeader::new(Metadata::new(source)?, path, contents);
+        header.compressed_size = compressed.len() as u64;
+        header.compression = compression;
+        header.hash = Sha256::digest(uncompressed).into();
+
+        write_record(& mut self.fp, &self.path, &header)?;
+        self.fp.write_all(compressed).map_err(ArchiveError::io(&self.path, Some(position)))?;
+
+        //Add position of the header to list
+        self.record(&header, position);
+
+        Ok(())
+
+    }
+
+    //Commit the version. The appender is consumed, so nothing can be written after the commit and
+    //the same version can't be committed twice. Dropping an appender without calling `finish`
+    //leaves the committed versions as they were
+    pub fn finish(mut self) -> Result<()> {
+
+        let version_header_offset = self.position()?;
+
+        //Append the version header
+        write_record(& mut self.fp, &self.path, &self.version_header)?;
+
+        //Append what this version stored first to the content index, chained to the committed records
+        if !self.added.is_empty() {
+            self.added.previous = self.backup_directory.contents;
+            self.backup_directory.contents = Some(self.position()?);
+            write_record(& mut self.fp, &self.path, &self.added)?;
+        }
+
+        //Make sure the payloads and version header are on disk before anything refers to them
+        self.fp.sync_data().map_err(ArchiveError::io(&self.path, None))?;
+
+        //Get the offset of the new version directory
+        let directory_offset = self.position()?;
+
+        //Add the new entry in the version directory
+        self.backup_directory.add(self.version_header.number, version_header_offset);
+
+        //append the new version directory, leaving the old one untouched
+        self.backup_directory.write(& mut self.fp, &self.path)?;
+        self.fp.sync_data().map_err(ArchiveError::io(&self.path, Some(directory_offset)))?;
+
+        //point the superblock at the new version directory, committing the version
+        self.superblock.directory_offset = directory_offset;
+        self.superblock.write(& mut self.fp, &self.path)?;
+
+        info!("committed version {} to '{}' with {} entries", self.version_header.number, self.path.display(), self.version_header.files.len());
+
+        Ok(())
+    }
+
+}
+
+//Headers are read from the archive the first time they are needed and kept for later lookups, so
+//opening an archive only reads the superblock and the version directory
+pub struct ReadArchive {
+    fp: File,
+    path: PathBuf,
+    directory: VersionDirectory,
+    versions: HashMap<VersionNumber, VersionHeader>, //Version headers read so far
+    files: HashMap<u64, (u64, FileHeader)>, //File headers read so far by offset, with the offset of their payload
+}
+
+impl ReadArchive {
+    fn new(archive_path: & Path) -> Result<Self> {
+
+        let mut fp = OpenOptions::new()
+            .read(true)
+            .open(archive_path).map_err(ArchiveError::io(archive_path, None))?;
+
+        //Get the superblock and the committed version directory, recovering the last intact one if needed
+        let (superblock, directory) = VersionDirectory::load(& mut fp, archive_path)?;
+
+        debug!("opened archive '{}' for reading: {} versions, directory at offset {}", archive_path.display(), directory.directory().len(), superblock.directory_offset);
+
+        Ok(ReadArchive {
+            fp,
+            path: PathBuf::from(archive_path),
+            directory,
+            versions: HashMap::new(),
+            files: HashMap::new(),
+        })
+    }
+
+    //Get the header of a version, reading it if it hasn't been already
+    fn version(& mut self, version: VersionNumber) -> Result<& VersionHeader> {
+
+        if !self.versions.contains_key(&version) {
+            let offset = self.directory.find(version).ok_or(ArchiveError::VersionNotFound(version))?;
+
+            let header = read_version_header(& mut self.fp, &self.path, offset).map_err(|error| error.within(version, None))?;
+
+            self.versions.insert(version, header);
+        }
+
+        Ok(&self.versions[&version])
+    }
+
+    //Get the offset of the header of `path` in a version, reading the header if it hasn't been already
+    fn entry(& mut self, version: VersionNumber, path: & Path) -> Result<u64> {
+
+        let not_found = || ArchiveError::FileNotFound { version, path: PathBuf::from(path) };
+
+        //Paths are looked up in the form they are stored in, so "./a\b" finds "a/b" on Windows
+        let name = normalise(path).map_err(|_| not_found())?;
+
+        let offset = self.version(version)?
+            .get(&name)
+            .filter(|entry| !entry.deleted)
+            .ok_or_else(not_found)?
+            .offset;
+
+        self.load(version, path, offset)?;
+
+        Ok(offset)
+    }
+
+    //Read the file header at `offset` into the cache, if it isn't there already
+    fn load(& mut self, version: VersionNumber, path: & Path, offset: u64) -> Result<()> {
+
+        if !self.files.contains_key(&offset) {
+            let header = read_file_header(& mut self.fp, &self.path, offset).map_err(|error| error.within(version, Some(path)))?;
+
+            let payload_offset = self.fp.stream_position().map_err(ArchiveError::io(&self.path, Some(offset)))?;
+
+            self.files.insert(offset, (payload_offset, header));
+        }
+
+        Ok(())
+    }
+
+    //Follow duplicates from the header at `offset` to the header holding the contents, reading
+    //every header on the way into the cache
+    fn resolve(& mut self, version: VersionNumber, path: & Path, offset: u64) -> Result<u64> {
+
+        let mut offset = offset;
+
+        while let Contents::Duplicate { target } = self.files[&offset].1.contents {
+            offset = earlier_header(&self.path, offset, target).map_err(|error| error.within(version, Some(path)))?;
+
+            self.load(version, path, offset)?;
+        }
+
+        Ok(offset)
+    }
+
+    //List the versions in the archive, oldest first. Every version header is read
+    pub fn versions(& mut self) -> Result<impl Iterator<Item = VersionInfo<'_>>> {
+
+        for entry in self.directory.directory().to_vec() {
+            self.version(entry.number)?;
+        }
+
+        let versions = &self.versions;
+
+        Ok(self.directory.directory().iter().map(move |entry| {
+            let version = &versions[&entry.number];
+
+            VersionInfo {
+                number: version.number,
+                message: &version.message,
+                entries: version.files.iter().filter(|entry| !entry.deleted).count(),
+            }
+        }))
+    }
+
+    //The number of the most recent version, if there is one
+    pub fn latest(& self) -> Option<VersionNumber> {
+        self.directory.directory().last().map(|entry| entry.number)
+    }
+
+    //The number of the version appended before `version`, if there is one
+    pub fn previous(& self, version: VersionNumber) -> Option<VersionNumber> {
+        let directory = self.directory.directory();
+        let index = directory.partition_point(|entry| entry.number < version);
+
+        index.checked_sub(1).map(|index| directory[index].number)
+    }
+
+    //The number of the version appended after `version`, if there is one
+    pub fn next(& self, version: VersionNumber) -> Option<VersionNumber> {
+        let directory = self.directory.directory();
+        let index = directory.partition_point(|entry| entry.number <= version);
+
+        directory.get(index).map(|entry| entry.number)
+    }
+
+    //Whether the archive contains the version
+    pub fn contains(& self, version: VersionNumber) -> bool {
+        self.directory.find(version).is_some()
+    }
+
+    //List the entries of a version, sorted by path. Every file header in the version is read
+    pub fn entries(& mut self, version: VersionNumber) -> Result<impl Iterator<Item = EntryInfo<'_>>> {
+
+        let index = self.version(version)?.files.clone();
+
+        for entry in index.iter() {
+            self.load(version, &entry.path, entry.offset)?;
+        }
+
+        let files = &self.files;
+
+        Ok(self.versions[&version].files.iter().map(move |entry| EntryInfo::new(entry, &files[&entry.offset].1)))
+    }
+
+    //List the paths of a version with their sizes, sorted by path. Only the version header is read,
+    //so this is much cheaper than `entries` for versions with many entries
+    pub fn paths(& mut self, version: VersionNumber) -> Result<impl Iterator<Item = PathInfo<'_>>> {
+
+        Ok(self.version(version)?.files.iter().map(|entry| PathInfo {
+            path: &entry.path,
+            len: entry.len,
+            compressed_size: entry.compressed_size,
+            deleted: entry.deleted,
+        }))
+    }
+
+    //List the entries that differ from version `old` to version `new`, in path order. Entries are
+    //compared by their headers, so no contents are read, and only when the path table can't tell
+    pub fn diff(& mut self, old: VersionNumber, new: VersionNumber) -> Result<Vec<Change>> {
+
+        //Tombstones only say a path is missing, so they are left out like any other missing path
+        let present = |files: & [IndexEntry]| files.iter().filter(|entry| !entry.deleted).cloned().collect::<Vec<_>>();
+
+        let old_files = present(&self.version(old)?.files);
+        let new_files = present(&self.version(new)?.files);
+
+        let mut changes = Vec::new();
+        let (mut old_index, mut new_index) = (0, 0);
+
+        //Both tables are sorted by path, so walk them together
+        loop {
+            let change = match (old_files.get(old_index), new_files.get(new_index)) {
+                (None, None) => break,
+                (Some(entry), None) => {
+                    old_index += 1;
+                    Some((entry.path.clone(), ChangeKind::Removed))
+                }
+                (None, Some(entry)) => {
+                    new_index += 1;
+                    Some((entry.path.clone(), ChangeKind::Added))
+                }
+                (Some(old_entry), Some(new_entry)) => match old_entry.path.cmp(&new_entry.path) {
+                    std::cmp::Ordering::Less => {
+                        old_index += 1;
+                        Some((old_entry.path.clone(), ChangeKind::Removed))
+                    }
+                    std::cmp::Ordering::Greater => {
+                        new_index += 1;
+                        Some((new_entry.path.clone(), ChangeKind::Added))
+                    }
+                    std::cmp::Ordering::Equal => {
+                        old_index += 1;
+                        new_index += 1;
+
+                        //Entries kept by an incremental version share the header
+                        if old_entry.offset == new_entry.offset {
+                            None
+                        } else if old_entry.len != new_entry.len {
+                            Some((new_entry.path.clone(), ChangeKind::Modified))
+                        } else {
+                            self.load(old, &old_entry.path, old_entry.offset)?;
+                            self.load(new, &new_entry.path, new_entry.offset)?;
+
+                            diff::compare(&self.files[&old_entry.offset].1, &self.files[&new_entry.offset].1)
+                                .map(|kind| (new_entry.path.clone(), kind))
+                        }
+                    }
+                },
+            };
+
+            if let Some((path, kind)) = change {
+                changes.push(Change { path, kind });
+            }
+        }
+
+        Ok(changes)
+    }
+
+    //List the versions in which `path` was added, modified or removed, oldest first. Every version
+    //header is read, but only the headers of the path itself
+    pub fn history<P: AsRef<Path>>(& mut self, path: P) -> Result<Vec<Revision>> {
+
+        let name = normalise(path.as_ref())?;
+
+        let mut history = Vec::new();
+        let mut last: Option<u64> = None; //Header of the path in the version before, if it was there
+
+        for number in self.directory.directory().iter().map(|entry| entry.number).collect::<Vec<_>>() {
+
+            let entry = self.version(number)?.get(&name).cloned();
+            let message = self.versions[&number].message.clone();
+
+            if let Some(entry) = & entry {
+                self.load(number, &name, entry.offset)?;
+            }
+
+            let present = entry.as_ref().filter(|entry| !entry.deleted).map(|entry| entry.offset);
+
+            let change = match (entry, last) {
+                //Entries kept by an incremental version share the header
+                (Some(entry), Some(previous)) if !entry.deleted && entry.offset == previous => None,
+                (Some(entry), Some(previous)) if !entry.deleted => {
+                    diff::compare(&self.files[&previous].1, &self.files[&entry.offset].1)
+                        .map(|kind| (kind, entry.offset, None))
+                }
+                (Some(entry), None) if !entry.deleted => Some((ChangeKind::Added, entry.offset, None)),
+                //Removed by a tombstone, which records when
+                (Some(entry), Some(previous)) => Some((ChangeKind::Removed, previous, self.files[&entry.offset].1.metadata.modified)),
+                (None, Some(previous)) => Some((ChangeKind::Removed, previous, None)),
+                (_, None) => None,
+            };
+
+            if let Some((kind, offset, removed)) = change {
+                let metadata = &self.files[&offset].1.metadata;
+
+                history.push(Revision {
+                    version: number,
+                    message,
+                    kind,
+                    file_type: metadata.file_type,
+                    len: metadata.len,
+                    modified: if kind == ChangeKind::Removed { removed } else { metadata.modified },
+                });
+            }
+
+            last = present;
+        }
+
+        Ok(history)
+    }
+
+    //Walk every structure in the archive and decompress every payload, reporting all the problems found
+    pub fn verify(& mut self) -> Result<VerifyReport> {
+        verify::verify(& mut self.fp, &self.path)
+    }
+
+    //Get the patch policy that was in effect when the version was appended
+    pub fn policy(& mut self, version: VersionNumber) -> Result<PatchPolicy> {
+        self.version(version).map(|version| version.policy)
+    }
+
+    pub fn file<W: Write, P: AsRef<Path>>(& mut self, version: VersionNumber, path: P, writer: & mut W) -> Result<()> {
+
+        let entry = self.entry(version, path.as_ref())?;
+        let header_offset = self.resolve(version, path.as_ref(), entry)?;
+        let (offset, header) = &self.files[&header_offset];
+
+        //The contents are checked against the stored hash once they have all been written, so a
+        //damaged file is reported as an error after the writer has received the damaged data
+        match & header.contents {
+            Contents::Snapshot => {
+                let size = header.compressed_size;
+
+                self.fp.seek(SeekFrom::Start(*offset)).map_err(ArchiveError::io(&self.path, Some(*offset)))?;
+
+                let mut taken = std::io::Read::by_ref(&mut self.fp).take(size);
+                let mut hashing = HashingWriter::new(writer);
+
+                header.compression.check()?.decompress(& mut std::io::BufReader::new(& mut taken), & mut hashing)
+                    .map_err(|error| ArchiveError::decompression(&self.path, header_offset)(error).within(version, Some(path.as_ref())))?;
+
+                if hashing.finish() != header.hash {
+                    return Err(ArchiveError::damaged(&self.path, header_offset).within(version, Some(path.as_ref())));
+                }
+            }
+            Contents::Patch { .. } => {
+                let contents = reconstruct(& mut self.fp, &self.path, header_offset).map_err(|error| error.within(version, Some(path.as_ref())))?;
+
+                writer.write_all(&contents).map_err(ArchiveError::io(path.as_ref(), None))?;
+            }
+            //Chunks are checked one at a time as they are read, and the whole contents once written
+            Contents::Chunked { chunks } => {
+                let mut hashing = HashingWriter::new(writer);
+                let mut len = 0;
+
+                for chunk in chunks.iter() {
+                    let contents = read_chunk(& mut self.fp, &self.path, *chunk).map_err(|error| error.within(version, Some(path.as_ref())))?;
+
+                    hashing.write_all(&contents).map_err(ArchiveError::io(path.as_ref(), None))?;
+                    len += contents.len() as u64;
+                }
+
+                if len != header.metadata.len || hashing.finish() != header.hash {
+                    return Err(ArchiveError::damaged(&self.path, header_offset).within(version, Some(path.as_ref())));
+                }
+            }
+            //Directories, links and tombstones have no contents, and duplicates have been resolved
+            Contents::Empty | Contents::Link { .. } | Contents::Duplicate { .. } | Contents::Deleted => {}
+        }
+
+        Ok(())
+    }
+
+    //Open an entry for reading, without writing its contents anywhere first. Damaged contents are
+    //reported as an `InvalidData` error wrapping an `ArchiveError`, before the end of the entry is read
+    pub fn open_file<P: AsRef<Path>>(& mut self, version: VersionNumber, path: P) -> Result<FileReader<'_>> {
+
+        let entry = self.entry(version, path.as_ref())?;
+        let header_offset = self.resolve(version, path.as_ref(), entry)?;
+        let (payload_offset, header) = &self.files[&header_offset];
+
+        match & header.contents {
+            Contents::Snapshot => {
+                let snapshot = reader::SnapshotReader::new(
+                    &self.fp,
+                    &self.path,
+                    version,
+                    path.as_ref(),
+                    header_offset,
+                    *payload_offset,
+                    header.compressed_size,
+                    header.compression.check()?,
+                    header.metadata.len,
+                    header.hash,
+                ).map_err(ArchiveError::decompression(&self.path, *payload_offset))?;
+
+                Ok(FileReader::snapshot(snapshot))
+            }
+            //Patches can only be applied to the whole of the base, so they are rebuilt up front
+            Contents::Patch { .. } => {
+                let contents = reconstruct(& mut self.fp, &self.path, header_offset).map_err(|error| error.within(version, Some(path.as_ref())))?;
+
+                Ok(FileReader::memory(contents))
+            }
+            Contents::Chunked { chunks } => {
+                if chunks.iter().map(|chunk| chunk.len).sum::<u64>() != header.metadata.len {
+                    return Err(ArchiveError::damaged(&self.path, header_offset).within(version, Some(path.as_ref())));
+                }
+
+                let chunked = reader::ChunkedReader::new(&self.fp, &self.path, version, path.as_ref(), chunks.clone())
+                    .map_err(ArchiveError::io(&self.path, Some(header_offset)))?;
+
+                Ok(FileReader::chunked(chunked))
+            }
+            //Directories, links and tombstones have no contents, and duplicates have been resolved
+            Contents::Empty | Contents::Link { .. } | Contents::Duplicate { .. } | Contents::Deleted => Ok(FileReader::memory(Vec::new())),
+        }
+    }
+
+    //Recreate a single entry of a version at `destination`: files are written out, directories are
+    //created and links are recreated as links. Missing parent directories are created, and the
+    //stored metadata is applied afterwards
+    pub fn restore<P: AsRef<Path>, Q: AsRef<Path>>(& mut self, version: VersionNumber, path: P, destination: Q) -> Result<()> {
+
+        let destination = destination.as_ref();
+
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent).map_err(ArchiveError::io(parent, None))?;
+        }
+
+        let metadata = self.materialise(version, path.as_ref(), destination)?;
+
+        apply_metadata(&metadata, destination)
+    }
+
+    //Recreate every entry of a version below `destination`, then apply the stored metadata. Metadata
+    //is applied children first, so writing into a directory doesn't change its times afterwards and
+    //read-only directories can still be filled
+    pub fn extract_version<P: AsRef<Path>>(& mut self, version: VersionNumber, destination: P) -> Result<()> {
+
+        let destination = destination.as_ref();
+
+        //The path table is sorted, which puts every directory before the entries inside it
+        //Tombstones are skipped, so removed paths stay removed
+        let paths = self.version(version)?
+            .files.iter().filter(|entry| !entry.deleted).map(|entry| entry.path.clone()).collect::<Vec<_>>();
+
+        info!("extracting version {} of '{}' to '{}', {} entries", version, self.path.display(), destination.display(), paths.len());
+
+        std::fs::create_dir_all(destination).map_err(ArchiveError::io(destination, None))?;
+
+        let mut restored = Vec::with_capacity(paths.len());
+
+        for path in paths {
+            //Never write outside the destination, whatever path was stored. Stored paths can't climb
+            //out with `..`, and nothing is written through a link, whether it was there already or
+            //was restored earlier in this extraction
+            let relative = normalise(&path)?;
+            let target = destination.join(&relative);
+
+            if let Some(link) = link_in_path(destination, &relative) {
+                return Err(ArchiveError::LinkInPath { path: target, link });
+            }
+
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent).map_err(ArchiveError::io(parent, None))?;
+            }
+
+            let metadata = self.materialise(version, &path, &target)?;
+
+            restored.push((target, metadata));
+        }
+
+        for (target, metadata) in restored.iter().rev() {
+            apply_metadata(metadata, target)?;
+        }
+
+        Ok(())
+    }
+
+    //Write out the contents of a single entry at `destination`, returning the metadata to apply to it
+    fn materialise(& mut self, version: VersionNumber, path: & Path, destination: & Path) -> Result<Metadata> {
+
+        let offset = self.entry(version, path)?;
+        let (_, header) = &self.files[&offset];
+
+        debug!("restoring '{}' from version {} to '{}'", path.display(), version, destination.display());
+
+        let metadata = header.metadata.clone();
+
+        match header.contents.clone() {
+            Contents::Link { target } => {
+                create_link(&target, destination).map_err(ArchiveError::io(destination, None))?;
+            }
+            Contents::Empty => {
+                std::fs::create_dir_all(destination).map_err(ArchiveError::io(destination, None))?;
+            }
+            _ => {
+                let mut fp = File::create(destination).map_err(ArchiveError::io(destination, None))?;
+
+                self.file(version, path, & mut fp)?;
+            }
+        }
 
-        Some(())
+        Ok(metadata)
     }
 }
@@ -0,0 +1,146 @@
+use std::fs::File;
+use std::path::Path;
+use std::io::{Read, Seek, SeekFrom, BufReader, ErrorKind};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use crate::error::{ArchiveError, Result};
+use super::{Compression, read_record};
+
+//Chunks are cut where the rolling hash of the bytes just read has its top bits clear, so the cuts
+//depend only on the contents around them. An edit changes the chunks it touches, and the chunks
+//before and after it still match the ones already stored
+pub(super) const MIN_CHUNK: usize = 16 * 1024;
+pub(super) const MAX_CHUNK: usize = 256 * 1024;
+const CUT_MASK: u64 = 0xFFFF << 48; //16 bits, so a cut every 64KiB on average after the minimum
+
+//A random value for every byte, mixed into the rolling hash. Generated by splitmix64 from a fixed
+//seed, the table must never change or stored chunks would stop matching
+const GEAR: [u64; 256] = gear();
+
+const fn gear() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0;
+    let mut index = 0;
+
+    while index < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+
+        let mut value = state;
+        value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+
+        table[index] = value ^ (value >> 31);
+        index += 1;
+    }
+
+    table
+}
+
+//Where one chunk of a chunked entry is stored, and how long it is uncompressed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub(super) struct Chunk {
+    pub(super) offset: u64, //Offset of the chunk header
+    pub(super) len: u64,
+}
+
+//Written in front of the payload of every chunk in the archive
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(super) struct ChunkHeader {
+    pub(super) compressed_size: u64,
+    pub(super) compression: Compression,
+    pub(super) len: u64, //Uncompressed length
+    pub(super) hash: [u8; 32], //SHA-256 of the uncompressed chunk
+}
+
+//Splits everything read from `inner` into content defined chunks
+pub(super) struct Chunker<R> {
+    inner: R,
+    buffer: Vec<u8>, //Read but not yet returned
+    end: bool, //Set once `inner` has run out
+}
+
+impl<R: Read> Chunker<R> {
+    pub(super) fn new(inner: R) -> Self {
+        Chunker { inner, buffer: Vec::new(), end: false }
+    }
+
+    pub(super) fn into_inner(self) -> R { self.inner }
+
+    //The next chunk, or None once everything has been returned. Chunks are never empty
+    pub(super) fn next_chunk(& mut self) -> std::io::Result<Option<Vec<u8>>> {
+
+        //Keep the largest possible chunk buffered, so the cut can always be found
+        while !self.end && self.buffer.len() < MAX_CHUNK {
+            let start = self.buffer.len();
+            self.buffer.resize(MAX_CHUNK, 0);
+
+            let read = self.inner.read(& mut self.buffer[start..]);
+
+            match read {
+                Ok(0) => {
+                    self.buffer.truncate(start);
+                    self.end = true;
+                }
+                Ok(count) => self.buffer.truncate(start + count),
+                Err(error) if error.kind() == ErrorKind::Interrupted => self.buffer.truncate(start),
+                Err(error) => {
+                    self.buffer.truncate(start);
+                    return Err(error);
+                }
+            }
+        }
+
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+
+        let rest = self.buffer.split_off(cut(&self.buffer));
+
+        Ok(Some(std::mem::replace(& mut self.buffer, rest)))
+    }
+}
+
+//Find the length of the chunk at the start of `data`
+fn cut(data: & [u8]) -> usize {
+
+    if data.len() <= MIN_CHUNK {
+        return data.len();
+    }
+
+    //Each byte is shifted out of the hash after 64 more, so starting just before the minimum gives
+    //the same cuts as hashing from the start of the chunk
+    let mut hash = 0u64;
+
+    for (index, byte) in data.iter().enumerate().take(MAX_CHUNK).skip(MIN_CHUNK - 64) {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+
+        if index >= MIN_CHUNK && hash & CUT_MASK == 0 {
+            return index + 1;
+        }
+    }
+
+    std::cmp::min(data.len(), MAX_CHUNK)
+}
+
+//Read, decompress and check a stored chunk
+pub(super) fn read_chunk(fp: & mut File, archive_path: & Path, chunk: Chunk) -> Result<Vec<u8>> {
+
+    fp.seek(SeekFrom::Start(chunk.offset)).map_err(ArchiveError::io(archive_path, Some(chunk.offset)))?;
+
+    let header: ChunkHeader = read_record(fp, archive_path)?;
+
+    if header.len != chunk.len {
+        return Err(ArchiveError::damaged(archive_path, chunk.offset));
+    }
+
+    let mut contents = Vec::with_capacity(std::cmp::min(header.len, MAX_CHUNK as u64) as usize);
+
+    let mut taken = Read::by_ref(fp).take(header.compressed_size);
+    header.compression.check()?.decompress(& mut BufReader::new(& mut taken), & mut contents).map_err(ArchiveError::decompression(archive_path, chunk.offset))?;
+
+    if contents.len() as u64 != header.len || <[u8; 32]>::from(Sha256::digest(&contents)) != header.hash {
+        return Err(ArchiveError::damaged(archive_path, chunk.offset));
+    }
+
+    Ok(contents)
+}
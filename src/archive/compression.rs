@@ -0,0 +1,321 @@
+use std::fmt;
+use std::io::{BufRead, Read, Write};
+use serde::{Serialize, Deserialize};
+use crate::error::{ArchiveError, Result};
+
+//How a payload is compressed, recorded in every file header. All methods can be named in any build,
+//but only those whose cargo feature is enabled can be read or written
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Stored,
+    Lzma, //Needs the `lzma` feature
+    Xz, //Needs the `lzma` feature
+    Deflate, //Needs the `deflate` feature
+    Zstd, //Needs the `zstd` feature
+}
+
+impl Default for Compression {
+    //The best compression available in this build, LZMA if it is enabled
+    fn default() -> Self {
+        if cfg!(feature = "lzma") {
+            Compression::Lzma
+        } else if cfg!(feature = "zstd") {
+            Compression::Zstd
+        } else if cfg!(feature = "deflate") {
+            Compression::Deflate
+        } else {
+            Compression::Stored
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(& self, f: & mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::Stored => write!(f, "stored"),
+            Compression::Lzma => write!(f, "lzma"),
+            Compression::Xz => write!(f, "xz"),
+            Compression::Deflate => write!(f, "deflate"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+//Level used for zstd, its default
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+impl Compression {
+    //Whether this build can compress and decompress with the method
+    pub fn is_supported(& self) -> bool {
+        match self {
+            Compression::Stored => true,
+            Compression::Lzma | Compression::Xz => cfg!(feature = "lzma"),
+            Compression::Deflate => cfg!(feature = "deflate"),
+            Compression::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+
+    //Fail with `UnsupportedCompression` if this build can't handle the method
+    pub(crate) fn check(self) -> Result<Self> {
+        if self.is_supported() {
+            Ok(self)
+        } else {
+            Err(ArchiveError::UnsupportedCompression(self))
+        }
+    }
+
+    //Compress everything read from `reader` into `writer`
+    pub(crate) fn compress<R: BufRead, W: Write>(self, reader: & mut R, writer: & mut W) -> std::io::Result<()> {
+        match self {
+            Compression::Stored => std::io::copy(reader, writer).map(|_| ()),
+            #[cfg(feature = "lzma")]
+            Compression::Lzma => lzma_rs::lzma_compress(reader, writer),
+            #[cfg(feature = "lzma")]
+            Compression::Xz => lzma_rs::xz_compress(reader, writer),
+            #[cfg(feature = "deflate")]
+            Compression::Deflate => {
+                let mut encoder = flate2::write::DeflateEncoder::new(writer, flate2::Compression::default());
+                std::io::copy(reader, & mut encoder)?;
+                encoder.finish().map(|_| ())
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::stream::copy_encode(reader, writer, ZSTD_LEVEL),
+            #[allow(unreachable_patterns)]
+            compression => Err(unsupported(compression)),
+        }
+    }
+
+    //Compress `contents` in memory
+    pub(crate) fn compress_slice(self, contents: & [u8]) -> std::io::Result<Vec<u8>> {
+        let mut compressed = Vec::new();
+        self.compress(& mut & contents[..], & mut compressed)?;
+        Ok(compressed)
+    }
+
+    //Decompress a payload from `reader` into `writer`. Only as much of `reader` as the payload needs
+    //is consumed where the method allows it, so trailing bytes can be detected
+    pub(crate) fn decompress<R: BufRead, W: Write>(self, reader: & mut R, writer: & mut W) -> std::io::Result<()> {
+        match self {
+            Compression::Stored => std::io::copy(reader, writer).map(|_| ()),
+            #[cfg(feature = "lzma")]
+            Compression::Lzma => lzma_rs::lzma_decompress(reader, writer).map_err(lzma_error),
+            #[cfg(feature = "lzma")]
+            Compression::Xz => lzma_rs::xz_decompress(reader, writer).map_err(lzma_error),
+            #[cfg(feature = "deflate")]
+            Compression::Deflate => std::io::copy(& mut flate2::bufread::DeflateDecoder::new(reader), writer).map(|_| ()),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => std::io::copy(& mut zstd::stream::read::Decoder::with_buffer(reader)?.single_frame(), writer).map(|_| ()),
+            #[allow(unreachable_patterns)]
+            compression => Err(unsupported(compression)),
+        }
+    }
+
+    //Wrap `reader` so that reading it yields the decompressed payload. xz payloads are decompressed
+    //in full up front, as there is no incremental xz decoder available
+    pub(crate) fn decoder<'a, R: BufRead + 'a>(self, reader: R) -> std::io::Result<Box<dyn Read + 'a>> {
+        match self {
+            Compression::Stored => Ok(Box::new(reader)),
+            #[cfg(feature = "lzma")]
+            Compression::Lzma => Ok(Box::new(LzmaReader::new(reader))),
+            #[cfg(feature = "lzma")]
+            Compression::Xz => {
+                let mut reader = reader;
+                let mut contents = Vec::new();
+                self.decompress(& mut reader, & mut contents)?;
+                Ok(Box::new(std::io::Cursor::new(contents)))
+            }
+            #[cfg(feature = "deflate")]
+            Compression::Deflate => Ok(Box::new(flate2::bufread::DeflateDecoder::new(reader))),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(Box::new(zstd::stream::read::Decoder::with_buffer(reader)?.single_frame())),
+            #[allow(unreachable_patterns)]
+            compression => Err(unsupported(compression)),
+        }
+    }
+}
+
+fn unsupported(compression: Compression) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Unsupported, ArchiveError::UnsupportedCompression(compression))
+}
+
+//Errors other than I/O mean the compressed data is damaged
+#[cfg(feature = "lzma")]
+fn lzma_error(error: lzma_rs::error::Error) -> std::io::Error {
+    match error {
+        lzma_rs::error::Error::IoError(error) => error,
+        error => std::io::Error::new(std::io::ErrorKind::InvalidData, error.to_string()),
+    }
+}
+
+//Size of the compressed chunks fed to the LZMA decoder
+#[cfg(feature = "lzma")]
+const CHUNK: usize = 64 * 1024;
+
+//Turns the push-based LZMA stream decoder into a reader. Output is produced a dictionary at a time,
+//so at most one dictionary of decompressed data is held in memory
+#[cfg(feature = "lzma")]
+struct LzmaReader<R> {
+    inner: R,
+    decoder: Option<lzma_rs::decompress::Stream<Vec<u8>>>, //None once the payload has been decompressed completely
+    output: Vec<u8>, //Decompressed bytes not yet read
+    consumed: usize, //Bytes of `output` already read
+}
+
+#[cfg(feature = "lzma")]
+impl<R: BufRead> LzmaReader<R> {
+    fn new(inner: R) -> Self {
+        LzmaReader {
+            inner,
+            decoder: Some(lzma_rs::decompress::Stream::new(Vec::new())),
+            output: Vec::new(),
+            consumed: 0,
+        }
+    }
+
+    //Decompress more of the payload into `output`, returning false once the end has been reached
+    fn fill(& mut self) -> std::io::Result<bool> {
+        self.output.clear();
+        self.consumed = 0;
+
+        let decoder = match self.decoder.as_mut() {
+            Some(decoder) => decoder,
+            None => return Ok(false),
+        };
+
+        let mut chunk = [0u8; CHUNK];
+        let count = self.inner.read(& mut chunk)?;
+
+        if count == 0 {
+            let decoder = self.decoder.take().expect("decoder is present until the payload ends");
+            self.output = decoder.finish().map_err(lzma_error)?;
+        } else {
+            let written = decoder.write_all(&chunk[..count]);
+
+            if let Some(output) = decoder.get_output_mut() {
+                std::mem::swap(output, & mut self.output);
+            }
+
+            written.map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))?;
+        }
+
+        Ok(true)
+    }
+}
+
+#[cfg(feature = "lzma")]
+impl<R: BufRead> Read for LzmaReader<R> {
+    fn read(& mut self, buf: & mut [u8]) -> std::io::Result<usize> {
+        while self.consumed == self.output.len() {
+            if !self.fill()? {
+                return Ok(0);
+            }
+        }
+
+        let count = std::cmp::min(buf.len(), self.output.len() - self.consumed);
+
+        buf[..count].copy_from_slice(&self.output[self.consumed..self.consumed + count]);
+        self.consumed += count;
+
+        Ok(count)
+    }
+}
+
+//Extensions of formats that are already compressed, stored as they are by default
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "avi", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg", "lz4", "lzma", "m4a", "mkv",
+    "mov", "mp3", "mp4", "ogg", "opus", "png", "pptx", "rar", "tbz2", "tgz", "txz", "webm", "webp", "whl", "xlsx", "xz",
+    "zip", "zst",
+];
+
+//Decides, per payload, whether the compression set on the appender is used or the payload is
+//stored as it is. Paths matching an include rule are always compressed, then paths matching an
+//exclude rule are always stored. Anything else is compressed only if a sample from its start
+//compresses well enough. Rules are matched against the name in the archive, ignoring case, and `*`
+//matches across `/`
+#[derive(Debug, Clone)]
+pub struct CompressionRules {
+    include: Vec<glob::Pattern>,
+    exclude: Vec<glob::Pattern>,
+    sample_size: usize, //Bytes sampled from the start of each payload, 0 disables sampling
+    max_sample_ratio: u32, //Largest compressed sample allowed, as a percentage of the sample
+}
+
+impl Default for CompressionRules {
+    //Store common already compressed formats and anything whose first 64KiB doesn't shrink by 5%
+    fn default() -> Self {
+        CompressionRules {
+            include: Vec::new(),
+            exclude: COMPRESSED_EXTENSIONS.iter()
+                .map(|extension| glob::Pattern::new(&format!("*.{}", extension)).expect("built in patterns are valid"))
+                .collect(),
+            sample_size: 64 * 1024,
+            max_sample_ratio: 95,
+        }
+    }
+}
+
+impl CompressionRules {
+    //Compress everything, as archives did before rules were added
+    pub fn none() -> Self {
+        CompressionRules {
+            include: Vec::new(),
+            exclude: Vec::new(),
+            sample_size: 0,
+            max_sample_ratio: 100,
+        }
+    }
+
+    //Always compress paths matching `pattern`
+    pub fn include(mut self, pattern: & str) -> Result<Self> {
+        self.include.push(parse(pattern)?);
+        Ok(self)
+    }
+
+    //Always store paths matching `pattern`, unless they match an include rule
+    pub fn exclude(mut self, pattern: & str) -> Result<Self> {
+        self.exclude.push(parse(pattern)?);
+        Ok(self)
+    }
+
+    //Sample the first `size` bytes of each payload, storing it if the sample compresses to more than
+    //`max_ratio` percent of its size. A size of 0 disables sampling
+    pub fn sample(mut self, size: usize, max_ratio: u32) -> Self {
+        self.sample_size = size;
+        self.max_sample_ratio = max_ratio;
+        self
+    }
+
+    pub(crate) fn sample_size(& self) -> usize { self.sample_size }
+
+    //Choose between `compression` and storing for the payload stored as `name` that starts with `sample`
+    pub(crate) fn choose(& self, compression: Compression, name: & std::path::Path, sample: & [u8]) -> std::io::Result<Compression> {
+        let options = glob::MatchOptions { case_sensitive: false, ..glob::MatchOptions::new() };
+        let matches = |patterns: & [glob::Pattern]| patterns.iter().any(|pattern| pattern.matches_path_with(name, options));
+
+        if compression == Compression::Stored || matches(&self.include) {
+            return Ok(compression);
+        }
+
+        if matches(&self.exclude) {
+            return Ok(Compression::Stored);
+        }
+
+        if self.sample_size == 0 {
+            return Ok(compression);
+        }
+
+        let sample = &sample[..std::cmp::min(sample.len(), self.sample_size)];
+        let compressed = compression.compress_slice(sample)?;
+
+        if compressed.len() as u64 * 100 > sample.len() as u64 * self.max_sample_ratio as u64 {
+            Ok(Compression::Stored)
+        } else {
+            Ok(compression)
+        }
+    }
+}
+
+fn parse(pattern: & str) -> Result<glob::Pattern> {
+    glob::Pattern::new(pattern).map_err(|error| ArchiveError::InvalidPattern { pattern: String::from(pattern), reason: error.to_string() })
+}
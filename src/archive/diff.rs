@@ -0,0 +1,70 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use super::{FileHeader, Contents, FileType, VersionNumber};
+
+//How an entry differs between the two versions compared by `ReadArchive::diff`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    //Only in the newer version
+    Added,
+    //Only in the older version, or removed by a tombstone
+    Removed,
+    //The type, length, contents or link target differ
+    Modified,
+    //The contents are the same, but the modification time or read-only flag differ
+    Metadata,
+}
+
+//A single entry that differs between two versions
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+}
+
+//A version in which a path changed, as listed by `ReadArchive::history`. For removals the type and
+//length are those of the entry removed, and the time is when it was removed if a tombstone says so
+#[derive(Debug, Clone)]
+pub struct Revision {
+    pub version: VersionNumber,
+    pub message: String, //Message of the version
+    pub kind: ChangeKind,
+    pub file_type: FileType,
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+}
+
+impl fmt::Display for ChangeKind {
+    fn fmt(& self, f: & mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChangeKind::Added => f.pad("added"),
+            ChangeKind::Removed => f.pad("removed"),
+            ChangeKind::Modified => f.pad("modified"),
+            ChangeKind::Metadata => f.pad("metadata"),
+        }
+    }
+}
+
+//Compare two headers for the same path, None if they are the same. Access times change whenever a
+//file is read, so they are ignored
+pub(super) fn compare(old: & FileHeader, new: & FileHeader) -> Option<ChangeKind> {
+
+    let target = |header: & FileHeader| match & header.contents {
+        Contents::Link { target } => Some(target.clone()),
+        _ => None,
+    };
+
+    if old.metadata.file_type != new.metadata.file_type
+        || old.metadata.len != new.metadata.len
+        || old.hash != new.hash
+        || target(old) != target(new) {
+        return Some(ChangeKind::Modified);
+    }
+
+    if old.metadata.modified != new.metadata.modified || old.metadata.read_only != new.metadata.read_only {
+        return Some(ChangeKind::Metadata);
+    }
+
+    None
+}
@@ -0,0 +1,281 @@
+use std::fs::File;
+use std::path::{PathBuf, Path};
+use std::io::{Read, Seek, SeekFrom, Cursor, BufReader, ErrorKind};
+use sha2::{Sha256, Digest};
+use crate::error::ArchiveError;
+use super::{VersionNumber, Compression};
+use super::chunks::{Chunk, read_chunk};
+
+//Reads the contents of an entry, as returned by `ReadArchive::open_file`. Snapshots are decompressed
+//as they are read, except for xz which is decompressed when the entry is opened. Patches are rebuilt
+//in full when the entry is opened. Seeking forwards decompresses and discards, seeking backwards
+//starts again from the beginning of the payload. Chunked entries are read a chunk at a time, and
+//seeking only reads the chunk it lands in
+pub struct FileReader<'a> {
+    inner: Inner<'a>,
+}
+
+enum Inner<'a> {
+    Memory(Cursor<Vec<u8>>),
+    Stream(Box<SnapshotReader<'a>>),
+    Chunked(Box<ChunkedReader>),
+}
+
+impl<'a> FileReader<'a> {
+    pub(super) fn memory(contents: Vec<u8>) -> Self {
+        FileReader { inner: Inner::Memory(Cursor::new(contents)) }
+    }
+
+    pub(super) fn snapshot(snapshot: SnapshotReader<'a>) -> Self {
+        FileReader { inner: Inner::Stream(Box::new(snapshot)) }
+    }
+
+    pub(super) fn chunked(chunked: ChunkedReader) -> Self {
+        FileReader { inner: Inner::Chunked(Box::new(chunked)) }
+    }
+}
+
+impl Read for FileReader<'_> {
+    fn read(& mut self, buf: & mut [u8]) -> std::io::Result<usize> {
+        match & mut self.inner {
+            Inner::Memory(cursor) => cursor.read(buf),
+            Inner::Stream(snapshot) => snapshot.read(buf),
+            Inner::Chunked(chunked) => chunked.read(buf),
+        }
+    }
+}
+
+impl Seek for FileReader<'_> {
+    fn seek(& mut self, position: SeekFrom) -> std::io::Result<u64> {
+        match & mut self.inner {
+            Inner::Memory(cursor) => cursor.seek(position),
+            Inner::Stream(snapshot) => snapshot.seek(position),
+            Inner::Chunked(chunked) => chunked.seek(position),
+        }
+    }
+}
+
+//Decompresses a snapshot payload on demand, checking the length and hash once the end is reached
+pub(super) struct SnapshotReader<'a> {
+    fp: File, //A handle of its own on the archive
+    path: PathBuf, //Path of the archive
+    version: VersionNumber,
+    name: PathBuf, //Path of the entry
+    header_offset: u64,
+    payload_offset: u64,
+    compressed_size: u64,
+    compression: Compression,
+    len: u64,
+    hash: [u8; 32],
+    decoder: Option<Box<dyn Read + 'a>>, //None once the payload has been decompressed completely
+    hasher: Sha256,
+    position: u64, //Position in the uncompressed contents
+}
+
+impl<'a> SnapshotReader<'a> {
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn new(fp: & File, path: & Path, version: VersionNumber, name: & Path, header_offset: u64, payload_offset: u64, compressed_size: u64, compression: Compression, len: u64, hash: [u8; 32]) -> std::io::Result<Self> {
+        let mut reader = SnapshotReader {
+            fp: fp.try_clone()?,
+            path: PathBuf::from(path),
+            version,
+            name: PathBuf::from(name),
+            header_offset,
+            payload_offset,
+            compressed_size,
+            compression,
+            len,
+            hash,
+            decoder: None,
+            hasher: Sha256::new(),
+            position: 0,
+        };
+
+        reader.rewind()?;
+
+        Ok(reader)
+    }
+
+    //Go back to the start of the payload with a fresh decoder
+    fn rewind(& mut self) -> std::io::Result<()> {
+        self.fp.seek(SeekFrom::Start(self.payload_offset))?;
+
+        let payload = BufReader::new(self.fp.try_clone()?.take(self.compressed_size));
+
+        self.decoder = Some(self.compression.decoder(payload).map_err(|error| self.damaged(error))?);
+        self.hasher = Sha256::new();
+        self.position = 0;
+
+        Ok(())
+    }
+
+    //Report decoder errors that mean the payload is damaged as a mismatch
+    fn damaged(& self, error: std::io::Error) -> std::io::Error {
+        if ArchiveError::is_damage(&error) {
+            self.mismatch()
+        } else {
+            error
+        }
+    }
+
+    //The error returned when the payload doesn't decompress to what the header describes
+    fn mismatch(& self) -> std::io::Error {
+        let error = ArchiveError::damaged(&self.path, self.header_offset).within(self.version, Some(&self.name));
+
+        std::io::Error::new(ErrorKind::InvalidData, error)
+    }
+}
+
+impl Read for SnapshotReader<'_> {
+    fn read(& mut self, buf: & mut [u8]) -> std::io::Result<usize> {
+        let decoder = match self.decoder.as_mut() {
+            Some(decoder) => decoder,
+            None => return Ok(0),
+        };
+
+        let count = match decoder.read(buf) {
+            Ok(count) => count,
+            Err(error) => return Err(self.damaged(error)),
+        };
+
+        self.hasher.update(&buf[..count]);
+        self.position += count as u64;
+
+        if self.position > self.len {
+            return Err(self.mismatch());
+        }
+
+        //Check the whole contents once the end has been reached
+        if count == 0 && !buf.is_empty() {
+            self.decoder = None;
+
+            let hash: [u8; 32] = std::mem::take(& mut self.hasher).finalize().into();
+
+            if self.position != self.len || hash != self.hash {
+                return Err(self.mismatch());
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+impl Seek for SnapshotReader<'_> {
+    fn seek(& mut self, position: SeekFrom) -> std::io::Result<u64> {
+        let target = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        let target = target.ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position"))?;
+
+        if target < self.position {
+            self.rewind()?;
+        }
+
+        //Decompress and discard up to the target, which may be past the end of the contents
+        let mut discard = [0u8; 8 * 1024];
+
+        while self.position < target {
+            let wanted = std::cmp::min(target - self.position, discard.len() as u64) as usize;
+
+            if self.read(& mut discard[..wanted])? == 0 {
+                self.position = target;
+                break;
+            }
+        }
+
+        Ok(self.position)
+    }
+}
+
+//Reads a chunked entry, loading one chunk at a time. Each chunk is checked against its own hash as it
+//is loaded, so damage is found without reading the rest of the entry
+pub(super) struct ChunkedReader {
+    fp: File, //A handle of its own on the archive
+    path: PathBuf, //Path of the archive
+    version: VersionNumber,
+    name: PathBuf, //Path of the entry
+    chunks: Vec<Chunk>,
+    starts: Vec<u64>, //Position of the start of each chunk in the contents
+    len: u64,
+    current: Option<(usize, Vec<u8>)>, //The chunk loaded last and its contents
+    position: u64, //Position in the contents
+}
+
+impl ChunkedReader {
+    pub(super) fn new(fp: & File, path: & Path, version: VersionNumber, name: & Path, chunks: Vec<Chunk>) -> std::io::Result<Self> {
+        let mut starts = Vec::with_capacity(chunks.len());
+        let mut len = 0;
+
+        for chunk in chunks.iter() {
+            starts.push(len);
+            len += chunk.len;
+        }
+
+        Ok(ChunkedReader {
+            fp: fp.try_clone()?,
+            path: PathBuf::from(path),
+            version,
+            name: PathBuf::from(name),
+            chunks,
+            starts,
+            len,
+            current: None,
+            position: 0,
+        })
+    }
+
+    //Load the chunk at `index`, unless it is loaded already
+    fn load(& mut self, index: usize) -> std::io::Result<& [u8]> {
+        if self.current.as_ref().is_none_or(|(loaded, _)| *loaded != index) {
+            let contents = read_chunk(& mut self.fp, &self.path, self.chunks[index]).map_err(|error| {
+                let kind = match error {
+                    ArchiveError::Io { .. } => ErrorKind::Other,
+                    _ => ErrorKind::InvalidData,
+                };
+
+                std::io::Error::new(kind, error.within(self.version, Some(&self.name)))
+            })?;
+
+            self.current = Some((index, contents));
+        }
+
+        Ok(self.current.as_ref().map_or(&[], |(_, contents)| contents.as_slice()))
+    }
+}
+
+impl Read for ChunkedReader {
+    fn read(& mut self, buf: & mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        //The last chunk starting at or before the position, chunks are never empty
+        let index = self.starts.partition_point(|start| *start <= self.position) - 1;
+        let skip = (self.position - self.starts[index]) as usize;
+
+        let contents = &self.load(index)?[skip..];
+        let count = std::cmp::min(contents.len(), buf.len());
+
+        buf[..count].copy_from_slice(&contents[..count]);
+        self.position += count as u64;
+
+        Ok(count)
+    }
+}
+
+impl Seek for ChunkedReader {
+    fn seek(& mut self, position: SeekFrom) -> std::io::Result<u64> {
+        let target = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        self.position = target.ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position"))?;
+
+        Ok(self.position)
+    }
+}
@@ -0,0 +1,1019 @@
+use std::fs;
+use std::path::{PathBuf, Path};
+use super::*;
+
+//A fresh directory under the system temporary directory, unique to this process and test
+fn scratch(name: & str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("gud_archive_{}_{}", std::process::id(), name));
+
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+
+    path
+}
+
+//Create an archive at `path` with one version per message, each storing a single file
+fn archive_with(path: & Path, messages: & [& str]) -> Archive {
+    let mut archive = Archive::new(path);
+
+    archive.create().unwrap();
+
+    for message in messages {
+        let mut appender = archive.appender_next(message.to_string()).unwrap();
+
+        appender.append_reader("file.txt", message.as_bytes(), Metadata::file()).unwrap();
+        appender.finish().unwrap();
+    }
+
+    archive
+}
+
+fn contents(archive: & mut Archive, version: u64, path: & str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    archive.reader().unwrap().file(VersionNumber::from(version), path, & mut bytes).unwrap();
+
+    bytes
+}
+
+#[test]
+fn every_directory_survives_appends() {
+    let root = scratch("directories");
+    let path = root.join("archive.gud");
+    let messages = ["one", "two", "three", "four", "five"];
+
+    let mut archive = archive_with(&path, &messages);
+
+    //Every append writes a new directory after the old ones, so all of them are still readable
+    let bytes = fs::read(&path).unwrap();
+    let offsets = bytes.windows(DIRECTORY_MAGIC.len())
+        .enumerate()
+        .filter(|(_, window)| *window == DIRECTORY_MAGIC)
+        .map(|(offset, _)| offset as u64)
+        .collect::<Vec<_>>();
+
+    assert_eq!(offsets.len(), messages.len() + 1);
+
+    let mut fp = File::open(&path).unwrap();
+
+    for (count, offset) in offsets.iter().enumerate() {
+        let directory = VersionDirectory::read(& mut fp, &path, *offset).unwrap();
+
+        assert_eq!(directory.directory().len(), count);
+    }
+
+    for (number, message) in messages.iter().enumerate() {
+        assert_eq!(contents(& mut archive, number as u64 + 1, "file.txt"), message.as_bytes());
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn recover_after_torn_superblock() {
+    let root = scratch("superblock");
+    let path = root.join("archive.gud");
+    let source = root.join("source");
+
+    fs::create_dir_all(source.join("dir")).unwrap();
+    fs::write(source.join("dir").join("a.txt"), b"a").unwrap();
+
+    let mut archive = archive_with(&path, &["one", "two"]);
+
+    let mut appender = archive.appender_next("three".to_string()).unwrap();
+
+    appender.set_root(&source);
+    appender.append_tree(".").unwrap();
+    appender.finish().unwrap();
+
+    //Tear the superblock by damaging the directory offset, which the checksum then fails to match
+    let mut bytes = fs::read(&path).unwrap();
+
+    bytes[20] ^= 0xFF;
+    fs::write(&path, &bytes).unwrap();
+
+    assert!(matches!(Superblock::read(& mut File::open(&path).unwrap(), &path), Err(ArchiveError::Damaged { .. })));
+
+    let mut reader = archive.reader().unwrap();
+
+    assert_eq!(reader.latest(), Some(VersionNumber::from(3)));
+    assert_eq!(reader.versions().unwrap().count(), 3);
+
+    assert_eq!(contents(& mut archive, 2, "file.txt"), b"two");
+    assert_eq!(contents(& mut archive, 3, "dir/a.txt"), b"a");
+
+    //The next append commits a fresh superblock that still requires the features already in use
+    let appender = archive.appender_next("four".to_string()).unwrap();
+
+    appender.finish().unwrap();
+
+    let superblock = Superblock::read(& mut File::open(&path).unwrap(), &path).unwrap();
+
+    assert_ne!(superblock.required_flags & REQUIRED_EMPTY_CONTENTS, 0);
+    assert_eq!(archive.reader().unwrap().latest(), Some(VersionNumber::from(4)));
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn other_major_version_is_not_recovered() {
+    let root = scratch("major");
+    let path = root.join("archive.gud");
+
+    let mut archive = archive_with(&path, &["one"]);
+
+    //A later format may lay the superblock out differently, so its checksum won't match either
+    let mut bytes = fs::read(&path).unwrap();
+
+    bytes[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(FORMAT_MAJOR + 1).to_le_bytes());
+    fs::write(&path, &bytes).unwrap();
+
+    assert!(matches!(archive.reader(), Err(ArchiveError::UnsupportedVersion { major, .. }) if major == FORMAT_MAJOR + 1));
+    assert!(matches!(archive.appender_next("two".to_string()), Err(ArchiveError::UnsupportedVersion { .. })));
+
+    assert_eq!(fs::read(&path).unwrap(), bytes);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+//The contents of a slowly changing text file at `version`
+fn revision(version: usize) -> Vec<u8> {
+    (0..200).map(|line| format!("line {} of version {}\n", line, if line == version % 200 { version } else { 0 })).collect::<String>().into_bytes()
+}
+
+//Append `source`/`name` to a new version with `append_patch`, after writing `contents` to it
+fn append_patched(archive: & mut Archive, source: & Path, name: & str, contents: & [u8]) {
+    fs::write(source.join(name), contents).unwrap();
+
+    let mut appender = archive.appender_next(String::new()).unwrap();
+
+    appender.set_root(source);
+    appender.append_patch(name).unwrap();
+    appender.finish().unwrap();
+}
+
+//The header `name` refers to in `version` of the archive at `path`, and its offset
+fn header(path: & Path, version: u64, name: & str) -> (u64, FileHeader) {
+    let offset = Archive::new(path).reader().unwrap().version(VersionNumber::from(version)).unwrap().get(Path::new(name)).unwrap().offset;
+
+    (offset, read_file_header(& mut File::open(path).unwrap(), path, offset).unwrap())
+}
+
+#[test]
+fn patch_chains_follow_their_depth() {
+    let root = scratch("chain");
+    let path = root.join("archive.gud");
+    let source = root.join("source");
+
+    fs::create_dir_all(&source).unwrap();
+
+    let mut archive = Archive::new(&path);
+
+    archive.create().unwrap();
+
+    for version in 1..=40 {
+        append_patched(& mut archive, &source, "file.txt", &revision(version));
+    }
+
+    for version in 1..=40 {
+        assert_eq!(contents(& mut archive, version as u64, "file.txt"), revision(version));
+    }
+
+    let (offset, mut last) = header(&path, 40, "file.txt");
+
+    assert!(matches!(last.contents, Contents::Patch { depth: 39, .. }));
+
+    //A header claiming a depth its base doesn't have is damage, not a longer chain
+    if let Contents::Patch { depth, .. } = & mut last.contents {
+        *depth = 7;
+    }
+
+    let mut fp = OpenOptions::new().write(true).open(&path).unwrap();
+
+    fp.seek(SeekFrom::Start(offset)).unwrap();
+    write_record(& mut fp, &path, &last).unwrap();
+
+    let mut bytes = Vec::new();
+
+    assert!(matches!(archive.reader().unwrap().file(VersionNumber::from(40), "file.txt", & mut bytes), Err(ArchiveError::Corrupt { .. })));
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+//The non-removed entries of `version`, with their types and link targets
+fn listing(archive: & mut Archive, version: u64) -> Vec<(PathBuf, FileType, Option<PathBuf>)> {
+    archive.reader().unwrap().entries(VersionNumber::from(version)).unwrap()
+        .filter(|entry| !entry.deleted)
+        .map(|entry| (entry.path.to_path_buf(), entry.file_type, entry.link_target.map(Path::to_path_buf)))
+        .collect()
+}
+
+#[cfg(unix)]
+#[test]
+fn tree_root_link_is_recorded_as_link() {
+    let root = scratch("root_link");
+    let path = root.join("archive.gud");
+    let source = root.join("source");
+    let elsewhere = root.join("elsewhere");
+
+    fs::create_dir_all(source.join("tree")).unwrap();
+    fs::create_dir_all(&elsewhere).unwrap();
+    fs::write(source.join("tree").join("x"), b"x").unwrap();
+    fs::write(elsewhere.join("x"), b"elsewhere").unwrap();
+
+    let mut archive = Archive::new(&path);
+
+    archive.create().unwrap();
+
+    let mut appender = archive.appender_next("directory".to_string()).unwrap();
+
+    appender.set_root(&source);
+    appender.append_tree("tree").unwrap();
+    appender.finish().unwrap();
+
+    fs::remove_dir_all(source.join("tree")).unwrap();
+    std::os::unix::fs::symlink(&elsewhere, source.join("tree")).unwrap();
+
+    //Both an incremental version, which removes what was below the directory, and a fresh one record
+    //the link alone and nothing it points to
+    for (version, incremental) in [(2, true), (3, false)] {
+        let mut appender = archive.appender_next("link".to_string()).unwrap();
+
+        appender.set_root(&source);
+
+        if incremental {
+            appender.set_incremental(ChangeDetection::Metadata);
+        }
+
+        appender.append_tree("tree").unwrap();
+        appender.finish().unwrap();
+
+        assert_eq!(listing(& mut archive, version), vec![(PathBuf::from("tree"), FileType::SystemLink, Some(elsewhere.clone()))]);
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn path_table_lists_sizes() {
+    let root = scratch("paths");
+    let path = root.join("archive.gud");
+
+    let mut archive = archive_with(&path, &["one", "three"]);
+
+    let mut reader = archive.reader().unwrap();
+
+    let paths = reader.paths(VersionNumber::from(2)).unwrap()
+        .map(|entry| (entry.path.to_path_buf(), entry.len, entry.compressed_size, entry.deleted))
+        .collect::<Vec<_>>();
+
+    let entries = reader.entries(VersionNumber::from(2)).unwrap()
+        .map(|entry| (entry.path.to_path_buf(), entry.len, entry.compressed_size, entry.deleted))
+        .collect::<Vec<_>>();
+
+    assert_eq!(paths, entries);
+    assert_eq!(paths[0].1, 5);
+
+    //The lengths differ, so the table alone shows the file was modified
+    let mut reader = archive.reader().unwrap();
+    let changes = reader.diff(VersionNumber::from(1), VersionNumber::from(2)).unwrap();
+
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].kind, ChangeKind::Modified);
+    assert!(reader.files.is_empty());
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+//Text long enough to be split into several chunks, varying with `seed`
+fn long_text(seed: usize) -> Vec<u8> {
+    (0..40_000).map(|line| format!("line {} of {}\n", line, (line * 7919 + seed) % 10_007)).collect::<String>().into_bytes()
+}
+
+//A policy that splits files of at least 64KiB into chunks
+fn chunking() -> PatchPolicy {
+    PatchPolicy { min_chunked_len: 64 * 1024, ..PatchPolicy::default() }
+}
+
+#[test]
+fn chunked_entries_list_their_chunks() {
+    let root = scratch("chunk_sizes");
+    let path = root.join("archive.gud");
+    let source = root.join("source");
+
+    fs::create_dir_all(&source).unwrap();
+    fs::write(source.join("a.txt"), long_text(0)).unwrap();
+    fs::write(source.join("b.txt"), long_text(0)).unwrap();
+
+    let mut archive = Archive::new(&path);
+
+    archive.create().unwrap();
+
+    let mut appender = archive.appender_next(String::new()).unwrap();
+
+    appender.set_root(&source);
+    appender.set_policy(chunking());
+    appender.append("a.txt").unwrap();
+    appender.append("b.txt").unwrap();
+    appender.finish().unwrap();
+
+    let mut reader = archive.reader().unwrap();
+    let entries = reader.entries(VersionNumber::from(1)).unwrap()
+        .map(|entry| (entry.chunks, entry.compressed_size, entry.len))
+        .collect::<Vec<_>>();
+
+    //The first copy stores its chunks, which are smaller if compressed, the second only refers to them
+    assert!(entries[0].0 > 1);
+    assert!(entries[0].1 > 0 && (entries[0].1 < entries[0].2 || Compression::default() == Compression::Stored));
+    assert_eq!((entries[1].0, entries[1].1), (0, 0));
+
+    let paths = reader.paths(VersionNumber::from(1)).unwrap().map(|entry| entry.compressed_size).collect::<Vec<_>>();
+
+    assert_eq!(paths, vec![entries[0].1, 0]);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+//Append `source`/`name` to a new version with `append` under `policy`, after writing `contents` to it
+fn append_with_policy(archive: & mut Archive, source: & Path, name: & str, contents: & [u8], policy: PatchPolicy) {
+    fs::write(source.join(name), contents).unwrap();
+
+    let mut appender = archive.appender_next(String::new()).unwrap();
+
+    appender.set_root(source);
+    appender.set_policy(policy);
+    appender.append(name).unwrap();
+    appender.finish().unwrap();
+}
+
+#[test]
+fn policy_limits_patch_chains() {
+    let root = scratch("policy");
+    let path = root.join("archive.gud");
+    let source = root.join("source");
+
+    fs::create_dir_all(&source).unwrap();
+
+    let mut archive = Archive::new(&path);
+
+    archive.create().unwrap();
+
+    //Uncompressed patches can be larger than the file, so allow any size in builds without compression
+    let policy = PatchPolicy { max_chain_length: 2, max_patch_ratio: 1000, ..PatchPolicy::default() };
+
+    for version in 1..=5 {
+        append_with_policy(& mut archive, &source, "file.txt", &revision(version), policy);
+    }
+
+    //A snapshot is forced once the chain reaches its maximum length
+    let depths = (1..=5).map(|version| match header(&path, version, "file.txt").1.contents {
+        Contents::Patch { depth, .. } => depth,
+        _ => 0,
+    }).collect::<Vec<_>>();
+
+    assert_eq!(depths, vec![0, 1, 2, 0, 1]);
+
+    //A patch larger than the ratio allows falls back to a snapshot
+    let strict = PatchPolicy { max_patch_ratio: 0, ..policy };
+
+    append_with_policy(& mut archive, &source, "file.txt", &revision(6), strict);
+
+    assert!(matches!(header(&path, 6, "file.txt").1.contents, Contents::Snapshot));
+    assert_eq!(archive.reader().unwrap().policy(VersionNumber::from(6)).unwrap(), strict);
+
+    for version in 1..=6 {
+        assert_eq!(contents(& mut archive, version as u64, "file.txt"), revision(version));
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+//Flip a byte in the middle of the only occurrence of `needle` in the archive at `path`
+fn damage(path: & Path, needle: & [u8]) {
+    let mut bytes = fs::read(path).unwrap();
+    let offset = bytes.windows(needle.len()).position(|window| window == needle).unwrap();
+
+    assert!(bytes[offset + 1..].windows(needle.len()).all(|window| window != needle));
+
+    bytes[offset + needle.len() / 2] ^= 0x01;
+    fs::write(path, &bytes).unwrap();
+}
+
+#[test]
+fn damaged_contents_are_reported() {
+    let root = scratch("hash");
+    let path = root.join("archive.gud");
+
+    let mut archive = Archive::new(&path);
+
+    archive.create().unwrap();
+
+    let mut appender = archive.appender_next(String::new()).unwrap();
+
+    appender.set_compression(Compression::Stored).unwrap();
+    appender.append_reader("a.txt", &b"contents of the first file"[..], Metadata::file()).unwrap();
+    appender.append_reader("b.txt", &b"contents of the second file"[..], Metadata::file()).unwrap();
+    appender.finish().unwrap();
+
+    damage(&path, b"contents of the second file");
+
+    //Only the damaged file fails, and the error says which version and file it is
+    assert_eq!(contents(& mut archive, 1, "a.txt"), b"contents of the first file");
+
+    let mut reader = archive.reader().unwrap();
+
+    let error = reader.file(VersionNumber::from(1), "b.txt", & mut Vec::new()).unwrap_err();
+
+    assert!(matches!(&error, ArchiveError::Damaged { version: Some(version), file: Some(file), .. } if *version == VersionNumber::from(1) && file == Path::new("b.txt")));
+
+    let mut streamed = Vec::new();
+
+    assert!(reader.open_file(VersionNumber::from(1), "b.txt").unwrap().read_to_end(& mut streamed).is_err());
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn extract_refuses_links_in_destination() {
+    let root = scratch("extract_link");
+    let path = root.join("archive.gud");
+    let source = root.join("source");
+    let outside = root.join("outside");
+    let destination = root.join("destination");
+
+    fs::create_dir_all(source.join("d")).unwrap();
+    fs::create_dir_all(&outside).unwrap();
+    fs::create_dir_all(&destination).unwrap();
+    fs::write(source.join("d").join("x"), b"x").unwrap();
+
+    let mut archive = Archive::new(&path);
+
+    archive.create().unwrap();
+
+    let mut appender = archive.appender_next(String::new()).unwrap();
+
+    appender.set_root(&source);
+    appender.append_tree("d").unwrap();
+    appender.finish().unwrap();
+
+    //A link already in the destination where the version has a directory would take `x` elsewhere
+    std::os::unix::fs::symlink(&outside, destination.join("d")).unwrap();
+
+    let error = archive.reader().unwrap().extract_version(VersionNumber::from(1), &destination).unwrap_err();
+
+    assert!(matches!(error, ArchiveError::LinkInPath { link, .. } if link == destination.join("d")));
+    assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+//Check reading `reader` after seeking backwards, forwards and past the end against `expected`
+fn check_seeks<R: Read + Seek>(mut reader: R, expected: & [u8]) {
+    let mut buffer = [0u8; 100];
+    let len = expected.len() as u64;
+
+    assert_eq!(reader.seek(SeekFrom::Start(20_000)).unwrap(), 20_000);
+    reader.read_exact(& mut buffer).unwrap();
+    assert_eq!(&buffer[..], &expected[20_000..20_100]);
+
+    assert_eq!(reader.seek(SeekFrom::Current(-15_100)).unwrap(), 5_000);
+    reader.read_exact(& mut buffer).unwrap();
+    assert_eq!(&buffer[..], &expected[5_000..5_100]);
+
+    assert_eq!(reader.seek(SeekFrom::Current(300_000)).unwrap(), 305_100);
+    reader.read_exact(& mut buffer).unwrap();
+    assert_eq!(&buffer[..], &expected[305_100..305_200]);
+
+    let mut tail = Vec::new();
+
+    assert_eq!(reader.seek(SeekFrom::End(-10)).unwrap(), len - 10);
+    reader.read_to_end(& mut tail).unwrap();
+    assert_eq!(tail, &expected[expected.len() - 10..]);
+
+    assert_eq!(reader.seek(SeekFrom::Start(len + 100)).unwrap(), len + 100);
+    assert_eq!(reader.read(& mut buffer).unwrap(), 0);
+
+    assert!(reader.seek(SeekFrom::End(-(len as i64) - 1)).is_err());
+
+    let mut all = Vec::new();
+
+    reader.seek(SeekFrom::Start(0)).unwrap();
+    reader.read_to_end(& mut all).unwrap();
+    assert_eq!(all, expected);
+}
+
+#[test]
+fn file_reader_seeks() {
+    let root = scratch("seek");
+    let path = root.join("archive.gud");
+    let source = root.join("source");
+
+    fs::create_dir_all(&source).unwrap();
+
+    for name in ["stored.txt", "compressed.txt", "patched.txt", "chunked.txt"] {
+        fs::write(source.join(name), long_text(1)).unwrap();
+    }
+
+    let mut archive = Archive::new(&path);
+
+    archive.create().unwrap();
+
+    let mut appender = archive.appender_next(String::new()).unwrap();
+
+    appender.set_root(&source);
+    appender.set_rules(CompressionRules::none().exclude("stored.txt").unwrap());
+    appender.append_snapshot("stored.txt").unwrap();
+    appender.append_snapshot("compressed.txt").unwrap();
+    appender.append_snapshot("patched.txt").unwrap();
+    appender.append_chunked("chunked.txt").unwrap();
+    appender.finish().unwrap();
+
+    append_patched(& mut archive, &source, "patched.txt", &long_text(2));
+
+    let mut reader = archive.reader().unwrap();
+
+    for (version, name, expected) in [(1, "stored.txt", long_text(1)), (1, "compressed.txt", long_text(1)), (2, "patched.txt", long_text(2)), (1, "chunked.txt", long_text(1))] {
+        check_seeks(reader.open_file(VersionNumber::from(version), name).unwrap(), &expected);
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn every_codec_round_trips() {
+    let root = scratch("codecs");
+    let path = root.join("archive.gud");
+
+    let mut archive = Archive::new(&path);
+
+    archive.create().unwrap();
+
+    for (seed, compression) in [Compression::Stored, Compression::Lzma, Compression::Xz, Compression::Deflate, Compression::Zstd].iter().copied().enumerate() {
+        //Different contents every time, so nothing is stored as a duplicate of an earlier version
+        let text = long_text(seed);
+        let mut appender = archive.appender_next(compression.to_string()).unwrap();
+
+        //Methods left out of this build are refused up front
+        if !compression.is_supported() {
+            assert!(matches!(appender.set_compression(compression), Err(ArchiveError::UnsupportedCompression(unsupported)) if unsupported == compression));
+            continue;
+        }
+
+        appender.set_compression(compression).unwrap();
+        appender.set_rules(CompressionRules::none());
+        appender.append_reader("file.txt", text.as_slice(), Metadata::file()).unwrap();
+        appender.finish().unwrap();
+
+        let mut reader = archive.reader().unwrap();
+        let latest = reader.latest().unwrap();
+
+        assert_eq!(reader.entries(latest).unwrap().next().unwrap().compression, compression);
+
+        let mut streamed = Vec::new();
+
+        reader.open_file(latest, "file.txt").unwrap().read_to_end(& mut streamed).unwrap();
+
+        assert_eq!(streamed, text);
+        assert_eq!(contents(& mut archive, latest.number, "file.txt"), text);
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+//Bytes that don't compress, from a fixed xorshift sequence
+fn noise(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_F491_4F6C_DD1Du64;
+
+    (0..len).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as u8
+    }).collect()
+}
+
+#[test]
+fn rules_choose_what_is_stored() {
+    let compression = Compression::default();
+
+    if compression == Compression::Stored {
+        return;
+    }
+
+    let text = long_text(0);
+    let noise = noise(128 * 1024);
+    let choose = |rules: & CompressionRules, name: & str, sample: & [u8]| rules.choose(compression, Path::new(name), sample).unwrap();
+
+    //Known compressed formats are stored whatever their case, and samples decide everything else
+    let rules = CompressionRules::default();
+
+    assert_eq!(choose(&rules, "photos/a.JPG", &text), Compression::Stored);
+    assert_eq!(choose(&rules, "notes.txt", &text), compression);
+    assert_eq!(choose(&rules, "data.bin", &noise), Compression::Stored);
+    assert_eq!(rules.choose(Compression::Stored, Path::new("notes.txt"), &text).unwrap(), Compression::Stored);
+
+    //Include rules win over exclude rules, and `*` matches across directories
+    let rules = CompressionRules::default().include("raw/*.jpg").unwrap().exclude("*.log").unwrap();
+
+    assert_eq!(choose(&rules, "raw/2024/a.jpg", &text), compression);
+    assert_eq!(choose(&rules, "raw/2024/a.bin", &noise), Compression::Stored);
+    assert_eq!(choose(&rules, "logs/today.log", &text), Compression::Stored);
+
+    //Without sampling everything not excluded is compressed
+    assert_eq!(choose(&CompressionRules::default().sample(0, 0), "data.bin", &noise), compression);
+
+    assert!(matches!(CompressionRules::none().include("[z-a"), Err(ArchiveError::InvalidPattern { .. })));
+}
+
+#[test]
+fn incompressible_files_are_stored() {
+    let root = scratch("rules");
+    let path = root.join("archive.gud");
+
+    let mut archive = Archive::new(&path);
+
+    archive.create().unwrap();
+
+    let mut appender = archive.appender_next(String::new()).unwrap();
+
+    appender.append_reader("data.bin", noise(128 * 1024).as_slice(), Metadata::file()).unwrap();
+    appender.append_reader("notes.txt", long_text(0).as_slice(), Metadata::file()).unwrap();
+    appender.finish().unwrap();
+
+    let compressions = archive.reader().unwrap().entries(VersionNumber::from(1)).unwrap().map(|entry| entry.compression).collect::<Vec<_>>();
+
+    assert_eq!(compressions, vec![Compression::Stored, Compression::default()]);
+    assert_eq!(contents(& mut archive, 1, "data.bin"), noise(128 * 1024));
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn identical_contents_are_stored_once() {
+    let root = scratch("dedup");
+    let path = root.join("archive.gud");
+    let source = root.join("source");
+
+    fs::create_dir_all(&source).unwrap();
+    fs::write(source.join("copy.txt"), long_text(4)).unwrap();
+
+    let mut archive = Archive::new(&path);
+
+    archive.create().unwrap();
+
+    let mut appender = archive.appender_next(String::new()).unwrap();
+
+    appender.set_compression(Compression::Stored).unwrap();
+
+    appender.append_reader("a.txt", long_text(4).as_slice(), Metadata::file()).unwrap();
+    appender.append_reader("b.txt", long_text(4).as_slice(), Metadata::file()).unwrap();
+    appender.finish().unwrap();
+
+    let stored = header(&path, 1, "a.txt").0;
+    let size = fs::metadata(&path).unwrap().len();
+
+    //Later versions find contents stored by earlier ones, whether appended from a reader or a file
+    let mut appender = archive.appender_next(String::new()).unwrap();
+
+    appender.set_compression(Compression::Stored).unwrap();
+
+    appender.set_root(&source);
+    appender.append_reader("c.txt", long_text(4).as_slice(), Metadata::file()).unwrap();
+    appender.append_snapshot("copy.txt").unwrap();
+    appender.append_reader("d.txt", long_text(5).as_slice(), Metadata::file()).unwrap();
+    appender.finish().unwrap();
+
+    //Each version adds its own record to the content index, and all of them are found
+    let mut appender = archive.appender_next(String::new()).unwrap();
+
+    appender.set_compression(Compression::Stored).unwrap();
+
+    appender.append_reader("e.txt", long_text(5).as_slice(), Metadata::file()).unwrap();
+    appender.finish().unwrap();
+
+    for (version, name) in [(1, "b.txt"), (2, "c.txt"), (2, "copy.txt")] {
+        assert!(matches!(header(&path, version, name).1.contents, Contents::Duplicate { target } if target == stored));
+    }
+
+    let (stored, _) = header(&path, 2, "d.txt");
+
+    assert!(matches!(header(&path, 3, "e.txt").1.contents, Contents::Duplicate { target } if target == stored));
+
+    for (version, name, expected) in [(1, "b.txt", long_text(4)), (2, "copy.txt", long_text(4)), (3, "e.txt", long_text(5))] {
+        assert_eq!(contents(& mut archive, version, name), expected);
+    }
+
+    //Only the new contents of version 2 were stored, once
+    let grown = fs::metadata(&path).unwrap().len() - size;
+    let new_size = archive.reader().unwrap().paths(VersionNumber::from(2)).unwrap().find(|entry| entry.path == Path::new("d.txt")).unwrap().compressed_size;
+
+    assert!(grown < new_size + 4096);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+//Where `Chunker` cuts `data`, as offsets from its start
+fn cuts(data: & [u8]) -> Vec<usize> {
+    let mut chunker = Chunker::new(data);
+    let mut cuts = Vec::new();
+    let mut end = 0;
+
+    while let Some(chunk) = chunker.next_chunk().unwrap() {
+        end += chunk.len();
+        cuts.push(end);
+    }
+
+    cuts
+}
+
+#[test]
+fn chunk_cuts_depend_only_on_nearby_contents() {
+    let data = noise(4 * 1024 * 1024);
+    let original = cuts(&data);
+
+    //Every chunk but the last is within the limits, and together they cover everything
+    for (start, end) in std::iter::once(0).chain(original.iter().copied()).zip(original.iter().copied()) {
+        assert!(end - start <= chunks::MAX_CHUNK);
+        assert!(end - start >= chunks::MIN_CHUNK || end == data.len());
+    }
+
+    assert_eq!(original.last(), Some(&data.len()));
+    assert!(original.len() > 16);
+
+    //Inserting bytes moves the cuts after the insert along with the contents, so they still match
+    let mut inserted = data[..1_000_000].to_vec();
+
+    inserted.extend_from_slice(b"inserted");
+    inserted.extend_from_slice(&data[1_000_000..]);
+
+    let shifted = cuts(&inserted).into_iter()
+        .filter(|cut| *cut > 1_000_000 + chunks::MAX_CHUNK)
+        .map(|cut| cut - 8)
+        .collect::<Vec<_>>();
+
+    assert!(!shifted.is_empty());
+    assert!(original.ends_with(&shifted));
+}
+
+#[test]
+fn edited_file_reuses_its_chunks() {
+    let root = scratch("chunk_reuse");
+    let path = root.join("archive.gud");
+    let source = root.join("source");
+
+    fs::create_dir_all(&source).unwrap();
+
+    let data = noise(2 * 1024 * 1024);
+    let mut edited = data[..1_000_000].to_vec();
+
+    edited.extend_from_slice(b"inserted");
+    edited.extend_from_slice(&data[1_000_000..]);
+
+    let mut archive = Archive::new(&path);
+
+    archive.create().unwrap();
+
+    for bytes in [&data, &edited] {
+        fs::write(source.join("image.bin"), bytes).unwrap();
+
+        let mut appender = archive.appender_next(String::new()).unwrap();
+
+        appender.set_root(&source);
+        appender.set_compression(Compression::Stored).unwrap();
+        appender.append_chunked("image.bin").unwrap();
+        appender.finish().unwrap();
+    }
+
+    let offsets = |version| match header(&path, version, "image.bin").1.contents {
+        Contents::Chunked { chunks } => chunks.iter().map(|chunk| chunk.offset).collect::<Vec<_>>(),
+        contents => panic!("expected chunks, found {:?}", contents),
+    };
+
+    //Only the chunks around the insert are new, the rest are those of the first version
+    let (first, second) = (offsets(1), offsets(2));
+    let new = second.iter().filter(|offset| !first.contains(offset)).count();
+
+    assert!((1..=2).contains(&new), "{} of {} chunks are new", new, second.len());
+    assert_eq!(contents(& mut archive, 2, "image.bin"), edited);
+    assert_eq!(contents(& mut archive, 1, "image.bin"), data);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn extract_directory_replaced_by_link() {
+    let root = scratch("link");
+    let path = root.join("archive.gud");
+    let source = root.join("source");
+    let outside = root.join("outside");
+    let destination = root.join("destination");
+
+    fs::create_dir_all(source.join("src").join("d")).unwrap();
+    fs::create_dir_all(&outside).unwrap();
+    fs::write(source.join("src").join("d").join("x"), b"inside").unwrap();
+    fs::write(outside.join("x"), b"outside").unwrap();
+
+    let mut archive = Archive::new(&path);
+
+    archive.create().unwrap();
+
+    let mut appender = archive.appender_next("directory".to_string()).unwrap();
+
+    appender.set_root(&source);
+    appender.append_tree("src").unwrap();
+    appender.finish().unwrap();
+
+    //Replace the directory with a link pointing outside of the tree
+    fs::remove_dir_all(source.join("src").join("d")).unwrap();
+    std::os::unix::fs::symlink(&outside, source.join("src").join("d")).unwrap();
+
+    let mut appender = archive.appender_next("link".to_string()).unwrap();
+
+    appender.set_root(&source);
+    appender.set_incremental(ChangeDetection::Metadata);
+    appender.append_tree("src").unwrap();
+    appender.finish().unwrap();
+
+    let mut reader = archive.reader().unwrap();
+
+    let deleted = reader.entries(VersionNumber::from(2)).unwrap()
+        .find(|entry| entry.path == Path::new("src/d/x"))
+        .map(|entry| entry.deleted);
+
+    assert_eq!(deleted, Some(true));
+
+    reader.extract_version(VersionNumber::from(2), &destination).unwrap();
+
+    assert!(is_link(&destination.join("src").join("d")).unwrap());
+    assert_eq!(fs::read(outside.join("x")).unwrap(), b"outside");
+    assert_eq!(fs::read_dir(&outside).unwrap().count(), 1);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+//The paths of `version` and whether each was removed
+fn removals(archive: & mut Archive, version: u64) -> Vec<(PathBuf, bool)> {
+    archive.reader().unwrap().paths(VersionNumber::from(version)).unwrap().map(|entry| (entry.path.to_path_buf(), entry.deleted)).collect()
+}
+
+#[test]
+fn removed_paths_leave_tombstones() {
+    let root = scratch("tombstones");
+    let path = root.join("archive.gud");
+    let source = root.join("source");
+    let destination = root.join("destination");
+
+    fs::create_dir_all(source.join("dir")).unwrap();
+    fs::write(source.join("dir").join("a.txt"), b"a").unwrap();
+    fs::write(source.join("dir").join("b.txt"), b"b").unwrap();
+    fs::write(source.join("keep.txt"), b"keep").unwrap();
+
+    let mut archive = Archive::new(&path);
+
+    archive.create().unwrap();
+
+    let mut appender = archive.appender_next(String::new()).unwrap();
+
+    appender.set_root(&source);
+    appender.append_tree(".").unwrap();
+    appender.finish().unwrap();
+
+    //Removing a directory removes everything below it, and only paths in the version can be removed
+    let mut appender = archive.appender_next(String::new()).unwrap();
+
+    appender.set_incremental(ChangeDetection::Metadata);
+    appender.remove("dir").unwrap();
+
+    assert!(matches!(appender.remove("missing.txt"), Err(ArchiveError::FileNotFound { .. })));
+
+    appender.finish().unwrap();
+
+    //Tombstones aren't inherited, the path is simply missing from later versions
+    let mut appender = archive.appender_next(String::new()).unwrap();
+
+    appender.set_incremental(ChangeDetection::Metadata);
+    appender.finish().unwrap();
+
+    let path_of = |name: & str| PathBuf::from(name);
+
+    assert_eq!(removals(& mut archive, 2), vec![(path_of("dir"), true), (path_of("dir/a.txt"), true), (path_of("dir/b.txt"), true), (path_of("keep.txt"), false)]);
+    assert_eq!(removals(& mut archive, 3), vec![(path_of("keep.txt"), false)]);
+
+    let mut reader = archive.reader().unwrap();
+
+    assert_eq!(reader.versions().unwrap().map(|version| version.entries).collect::<Vec<_>>(), vec![4, 1, 1]);
+    assert!(matches!(reader.file(VersionNumber::from(2), "dir/a.txt", & mut Vec::new()), Err(ArchiveError::FileNotFound { .. })));
+
+    reader.extract_version(VersionNumber::from(2), &destination).unwrap();
+
+    assert_eq!(fs::read_dir(&destination).unwrap().map(|entry| entry.unwrap().file_name()).collect::<Vec<_>>(), vec!["keep.txt"]);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+//Append files with the given contents and modification times, in seconds, to a new version
+fn append_files(archive: & mut Archive, files: & [(& str, & str, u64)]) {
+    let mut appender = archive.appender_next(String::new()).unwrap();
+
+    for (name, text, modified) in files {
+        let metadata = Metadata { modified: Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(*modified)), ..Metadata::file() };
+
+        appender.append_reader(name, text.as_bytes(), metadata).unwrap();
+    }
+
+    appender.finish().unwrap();
+}
+
+//The changes from version `old` to version `new`
+fn changes(archive: & mut Archive, old: u64, new: u64) -> Vec<(PathBuf, ChangeKind)> {
+    archive.reader().unwrap().diff(VersionNumber::from(old), VersionNumber::from(new)).unwrap().into_iter().map(|change| (change.path, change.kind)).collect()
+}
+
+#[test]
+fn diff_compares_versions() {
+    let root = scratch("diff");
+    let path = root.join("archive.gud");
+
+    let mut archive = Archive::new(&path);
+
+    archive.create().unwrap();
+
+    append_files(& mut archive, &[("a.txt", "aaaa", 1), ("b.txt", "bbbb", 1), ("c.txt", "cccc", 1), ("d.txt", "dddd", 1)]);
+    append_files(& mut archive, &[("a.txt", "aaaa", 2), ("b.txt", "BBBB", 1), ("d.txt", "dddd", 1), ("e.txt", "eeee", 1)]);
+
+    let mut appender = archive.appender_next(String::new()).unwrap();
+
+    appender.set_incremental(ChangeDetection::Metadata);
+    appender.remove("b.txt").unwrap();
+    appender.finish().unwrap();
+
+    let path_of = |name: & str| PathBuf::from(name);
+
+    //Contents of the same length are told apart by their hashes, and times alone are only metadata
+    assert_eq!(changes(& mut archive, 1, 2), vec![
+        (path_of("a.txt"), ChangeKind::Metadata),
+        (path_of("b.txt"), ChangeKind::Modified),
+        (path_of("c.txt"), ChangeKind::Removed),
+        (path_of("e.txt"), ChangeKind::Added),
+    ]);
+
+    assert_eq!(changes(& mut archive, 2, 1), vec![
+        (path_of("a.txt"), ChangeKind::Metadata),
+        (path_of("b.txt"), ChangeKind::Modified),
+        (path_of("c.txt"), ChangeKind::Added),
+        (path_of("e.txt"), ChangeKind::Removed),
+    ]);
+
+    //A tombstone reads as a removal, and inherited entries are unchanged
+    assert_eq!(changes(& mut archive, 2, 3), vec![(path_of("b.txt"), ChangeKind::Removed)]);
+    assert_eq!(changes(& mut archive, 3, 3), vec![]);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn history_lists_changes_to_a_path() {
+    let root = scratch("history");
+    let path = root.join("archive.gud");
+
+    let mut archive = Archive::new(&path);
+
+    archive.create().unwrap();
+
+    append_files(& mut archive, &[("a.txt", "one", 1)]);
+    append_files(& mut archive, &[("a.txt", "one", 1), ("b.txt", "b", 1)]);
+    append_files(& mut archive, &[("a.txt", "two!", 2)]);
+
+    let mut appender = archive.appender_next(String::new()).unwrap();
+
+    appender.set_incremental(ChangeDetection::Metadata);
+    appender.remove("a.txt").unwrap();
+    appender.finish().unwrap();
+
+    append_files(& mut archive, &[("a.txt", "three", 3)]);
+    append_files(& mut archive, &[("b.txt", "b", 1)]);
+
+    let history = archive.reader().unwrap().history("./a.txt").unwrap();
+
+    let summary = history.iter().map(|revision| (revision.version.number, revision.kind, revision.len)).collect::<Vec<_>>();
+
+    //Versions where nothing changed are left out, and removals describe the entry removed
+    assert_eq!(summary, vec![
+        (1, ChangeKind::Added, 3),
+        (3, ChangeKind::Modified, 4),
+        (4, ChangeKind::Removed, 4),
+        (5, ChangeKind::Added, 5),
+        (6, ChangeKind::Removed, 5),
+    ]);
+
+    //Only a tombstone knows when the path was removed
+    assert!(history[2].modified.is_some());
+    assert!(history[4].modified.is_none());
+    assert_eq!(history[1].modified, Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(2)));
+
+    assert!(archive.reader().unwrap().history("missing.txt").unwrap().is_empty());
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,293 @@
+use std::fmt;
+use std::fs::File;
+use std::path::{PathBuf, Path};
+use std::io::{Read, Seek, Write};
+use std::collections::HashSet;
+use crate::error::{ArchiveError, Result};
+use super::{VersionNumber, Superblock, VersionDirectory, IndexEntry, FileHeader, Contents, HashingWriter, read_version_header, read_file_header, reconstruct, earlier_header};
+use super::chunks::read_chunk;
+
+//Everything found wrong with an archive by `ReadArchive::verify`
+#[derive(Debug)]
+pub struct VerifyReport {
+    pub versions: usize, //Number of version headers that could be read
+    pub files: usize, //Number of distinct file entries checked
+    pub problems: Vec<Problem>,
+}
+
+impl VerifyReport {
+    pub fn is_healthy(& self) -> bool { self.problems.is_empty() }
+}
+
+//A single problem, with the version and file it was found in where known
+#[derive(Debug)]
+pub struct Problem {
+    pub offset: u64,
+    pub version: Option<VersionNumber>,
+    pub file: Option<PathBuf>,
+    pub kind: ProblemKind,
+}
+
+#[derive(Debug)]
+pub enum ProblemKind {
+    //The superblock is unreadable, the archive may not be usable at all
+    Superblock(ArchiveError),
+    //The committed version directory is unreadable, the directory at `recovered` was used instead
+    Directory { error: ArchiveError, recovered: Option<u64> },
+    VersionHeader(ArchiveError),
+    FileHeader(ArchiveError),
+    //The payload could not be decompressed or patched
+    Payload(ArchiveError),
+    //The payload runs past the end of the archive, or the compressed stream ends before or after it should
+    CompressedSize { expected: u64, actual: u64 },
+    //The uncompressed contents are not as long as `Metadata::len` says
+    Length { expected: u64, actual: u64 },
+    //The uncompressed contents do not match the stored hash
+    Hash,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(& self, f: & mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "offset {}", self.offset)?;
+
+        if let Some(version) = self.version {
+            write!(f, ", version {}", version)?;
+        }
+
+        if let Some(file) = & self.file {
+            write!(f, ", '{}'", file.display())?;
+        }
+
+        match & self.kind {
+            ProblemKind::Superblock(error) => write!(f, ": bad superblock: {}", error),
+            ProblemKind::Directory { error, recovered: Some(recovered) } => write!(f, ": bad version directory, recovered the one at offset {}: {}", recovered, error),
+            ProblemKind::Directory { error, recovered: None } => write!(f, ": bad version directory, nothing could be recovered: {}", error),
+            ProblemKind::VersionHeader(error) => write!(f, ": bad version header: {}", error),
+            ProblemKind::FileHeader(error) => write!(f, ": bad file header: {}", error),
+            ProblemKind::Payload(error) => write!(f, ": bad payload: {}", error),
+            ProblemKind::CompressedSize { expected, actual } => write!(f, ": compressed size is {} but {} bytes were used", expected, actual),
+            ProblemKind::Length { expected, actual } => write!(f, ": length is {} but {} bytes were stored", expected, actual),
+            ProblemKind::Hash => write!(f, ": contents do not match their hash"),
+        }
+    }
+}
+
+//Counts everything written, discarding the data
+struct Sink {
+    length: u64,
+}
+
+impl Write for Sink {
+    fn write(& mut self, buf: & [u8]) -> std::io::Result<usize> {
+        self.length += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(& mut self) -> std::io::Result<()> { Ok(()) }
+}
+
+//Walks the whole archive, collecting problems instead of stopping at the first one
+pub(super) fn verify(fp: & mut File, archive_path: & Path) -> Result<VerifyReport> {
+
+    let mut report = VerifyReport { versions: 0, files: 0, problems: Vec::new() };
+
+    let archive_length = fp.metadata().map_err(ArchiveError::io(archive_path, None))?.len();
+
+    //Superblock. Without a recognisable one there is nothing more to check
+    let superblock = match Superblock::read(fp, archive_path) {
+        Ok(superblock) => Some(superblock),
+        Err(error @ ArchiveError::Damaged { .. }) => {
+            report.problems.push(Problem { offset: 0, version: None, file: None, kind: ProblemKind::Superblock(error) });
+            None
+        }
+        Err(error) => {
+            report.problems.push(Problem { offset: 0, version: None, file: None, kind: ProblemKind::Superblock(error) });
+            return Ok(report);
+        }
+    };
+
+    //Version directory, falling back to the last intact one like the reader does
+    let committed = superblock.as_ref().map(|superblock| VersionDirectory::read(fp, archive_path, superblock.directory_offset));
+
+    let directory = match committed {
+        Some(Ok(directory)) => directory,
+        committed => {
+            let offset = superblock.as_ref().map_or(0, |superblock| superblock.directory_offset);
+            let recovered = VersionDirectory::recover(fp, archive_path);
+
+            if let Some(Err(error)) = committed {
+                report.problems.push(Problem { offset, version: None, file: None, kind: ProblemKind::Directory {
+                    error,
+                    recovered: recovered.as_ref().ok().map(|(offset, _)| *offset),
+                }});
+            }
+
+            match recovered {
+                Ok((_, directory)) => directory,
+                Err(error) => {
+                    report.problems.push(Problem { offset, version: None, file: None, kind: ProblemKind::Directory { error, recovered: None } });
+                    return Ok(report);
+                }
+            }
+        }
+    };
+
+    //Entries shared between versions are only checked once
+    let mut checked = HashSet::new();
+
+    for entry in directory.directory().iter() {
+
+        let header = match read_version_header(fp, archive_path, entry.offset) {
+            Ok(header) => header,
+            Err(error) => {
+                report.problems.push(Problem { offset: entry.offset, version: Some(entry.number), file: None, kind: ProblemKind::VersionHeader(error) });
+                continue;
+            }
+        };
+
+        report.versions += 1;
+
+        for IndexEntry { path, offset: file_offset, .. } in header.files.iter() {
+
+            if !checked.insert(*file_offset) {
+                continue;
+            }
+
+            report.files += 1;
+
+            let problem = |kind| Problem { offset: *file_offset, version: Some(header.number), file: Some(path.clone()), kind };
+
+            let file_header = match read_file_header(fp, archive_path, *file_offset) {
+                Ok(file_header) => file_header,
+                Err(error) => {
+                    report.problems.push(problem(ProblemKind::FileHeader(error)));
+                    continue;
+                }
+            };
+
+            for kind in check_payload(fp, archive_path, archive_length, *file_offset, &file_header)? {
+                report.problems.push(problem(kind));
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+//Check the payload following the file header at `offset`, the archive is positioned just after the header
+fn check_payload(fp: & mut File, archive_path: & Path, archive_length: u64, offset: u64, header: & FileHeader) -> Result<Vec<ProblemKind>> {
+
+    let mut problems = Vec::new();
+
+    let start = fp.stream_position().map_err(ArchiveError::io(archive_path, Some(offset)))?;
+
+    //Entries without a payload, such as directories, links and tombstones, only need their size checking
+    if let Contents::Empty | Contents::Link { .. } | Contents::Deleted = header.contents {
+        if header.compressed_size != 0 {
+            problems.push(ProblemKind::CompressedSize { expected: header.compressed_size, actual: 0 });
+        }
+
+        if header.metadata.len != 0 {
+            problems.push(ProblemKind::Length { expected: header.metadata.len, actual: 0 });
+        }
+
+        return Ok(problems);
+    }
+
+    //Duplicates share the contents of an earlier entry, which is checked in its own right, so only
+    //the reference needs checking
+    if let Contents::Duplicate { target } = header.contents {
+        if header.compressed_size != 0 {
+            problems.push(ProblemKind::CompressedSize { expected: header.compressed_size, actual: 0 });
+        }
+
+        let original = earlier_header(archive_path, offset, target).and_then(|target| read_file_header(fp, archive_path, target));
+
+        match original {
+            Ok(original) if original.hash != header.hash => problems.push(ProblemKind::Hash),
+            Ok(original) if original.metadata.len != header.metadata.len => {
+                problems.push(ProblemKind::Length { expected: header.metadata.len, actual: original.metadata.len });
+            }
+            Ok(_) => {}
+            Err(error) => problems.push(ProblemKind::FileHeader(error)),
+        }
+
+        return Ok(problems);
+    }
+
+    //Chunked entries have no payload of their own, every chunk is read and checked instead
+    if let Contents::Chunked { chunks } = &header.contents {
+        if header.compressed_size != 0 {
+            problems.push(ProblemKind::CompressedSize { expected: header.compressed_size, actual: 0 });
+        }
+
+        let mut hashing = HashingWriter::new(Sink { length: 0 });
+
+        for chunk in chunks.iter() {
+            match read_chunk(fp, archive_path, *chunk) {
+                Ok(contents) => hashing.write_all(&contents).map_err(ArchiveError::io(archive_path, Some(chunk.offset)))?,
+                Err(error) => {
+                    problems.push(ProblemKind::Payload(error));
+                    return Ok(problems);
+                }
+            }
+        }
+
+        if hashing.inner.length != header.metadata.len {
+            problems.push(ProblemKind::Length { expected: header.metadata.len, actual: hashing.inner.length });
+        }
+
+        if hashing.finish() != header.hash {
+            problems.push(ProblemKind::Hash);
+        }
+
+        return Ok(problems);
+    }
+
+    if start + header.compressed_size > archive_length {
+        problems.push(ProblemKind::CompressedSize { expected: header.compressed_size, actual: archive_length - start });
+        return Ok(problems);
+    }
+
+    //Decompress this entry's own payload, checking the stream ends exactly where the header says
+    let mut taken = std::io::Read::by_ref(fp).take(header.compressed_size);
+    let mut buffered = std::io::BufReader::new(& mut taken);
+    let mut hashing = HashingWriter::new(Sink { length: 0 });
+
+    let decompressed = header.compression.check().and_then(|compression| {
+        compression.decompress(& mut buffered, & mut hashing).map_err(ArchiveError::decompression(archive_path, offset))
+    });
+
+    if let Err(error) = decompressed {
+        problems.push(ProblemKind::Payload(error));
+        return Ok(problems);
+    }
+
+    let unused = buffered.buffer().len() as u64 + buffered.into_inner().limit();
+
+    if unused != 0 {
+        problems.push(ProblemKind::CompressedSize { expected: header.compressed_size, actual: header.compressed_size - unused });
+    }
+
+    //Patches only hold a delta, so the full contents have to be rebuilt to check them
+    let (length, hash) = match header.contents {
+        Contents::Patch { .. } => match reconstruct(fp, archive_path, offset) {
+            Ok(contents) => (contents.len() as u64, header.hash),
+            Err(error) => {
+                problems.push(ProblemKind::Payload(error));
+                return Ok(problems);
+            }
+        },
+        _ => (hashing.inner.length, hashing.finish()),
+    };
+
+    if length != header.metadata.len {
+        problems.push(ProblemKind::Length { expected: header.metadata.len, actual: length });
+    }
+
+    if hash != header.hash {
+        problems.push(ProblemKind::Hash);
+    }
+
+    Ok(problems)
+}
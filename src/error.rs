@@ -0,0 +1,141 @@
+use std::fmt;
+use std::path::{PathBuf, Path};
+use crate::archive::{VersionNumber, Compression};
+
+pub type Result<T> = std::result::Result<T, ArchiveError>;
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    //Reading or writing the archive, or a file being appended, failed
+    Io { path: PathBuf, offset: Option<u64>, source: std::io::Error },
+    //The archive contains structures that make no sense
+    Corrupt { path: PathBuf, offset: u64, reason: String },
+    //The file does not start with the archive magic number
+    NotAnArchive(PathBuf),
+    //The archive was written with a major format version this library cannot read
+    UnsupportedVersion { path: PathBuf, major: u16, minor: u16 },
+    //The archive requires features this library does not know about
+    UnsupportedFeatures { path: PathBuf, flags: u32 },
+    //A checksum or content hash did not match, naming the version and file if known
+    Damaged { path: PathBuf, offset: u64, version: Option<VersionNumber>, file: Option<PathBuf> },
+    //A header in the archive could not be deserialized
+    Decode { path: PathBuf, offset: u64, source: bincode::Error },
+    //A payload could not be compressed, decompressed or patched
+    Compression { path: PathBuf, offset: u64, reason: String },
+    //A path given to the archive cannot be stored
+    InvalidPath(PathBuf),
+    //The requested version is not in the archive
+    VersionNotFound(VersionNumber),
+    //The requested file is not in the version
+    FileNotFound { version: VersionNumber, path: PathBuf },
+    //A compression rule is not a valid glob pattern
+    InvalidPattern { pattern: String, reason: String },
+    //The compression method is not enabled in this build
+    UnsupportedCompression(Compression),
+    //A version was appended with a number that is not greater than the latest one in the archive
+    VersionOrder { path: PathBuf, number: VersionNumber, latest: VersionNumber },
+    //Extracting to `path` would write through the symbolic link `link`, possibly outside the destination
+    LinkInPath { path: PathBuf, link: PathBuf },
+}
+
+impl ArchiveError {
+    pub(crate) fn io(path: & Path, offset: Option<u64>) -> impl FnOnce(std::io::Error) -> ArchiveError + '_ {
+        move |source| ArchiveError::Io { path: PathBuf::from(path), offset, source }
+    }
+
+    pub(crate) fn decode(path: & Path, offset: u64) -> impl FnOnce(bincode::Error) -> ArchiveError + '_ {
+        move |source| ArchiveError::Decode { path: PathBuf::from(path), offset, source }
+    }
+
+    //Serialization only fails if the underlying writer does, so report it as I/O where possible
+    pub(crate) fn encode(path: & Path, offset: u64) -> impl FnOnce(bincode::Error) -> ArchiveError + '_ {
+        move |source| match *source {
+            bincode::ErrorKind::Io(source) => ArchiveError::Io { path: PathBuf::from(path), offset: Some(offset), source },
+            _ => ArchiveError::Decode { path: PathBuf::from(path), offset, source },
+        }
+    }
+
+    pub(crate) fn damaged(path: & Path, offset: u64) -> ArchiveError {
+        ArchiveError::Damaged { path: PathBuf::from(path), offset, version: None, file: None }
+    }
+
+    //Name the version and file that were being read when a checksum failed
+    pub(crate) fn within(self, number: VersionNumber, name: Option<& Path>) -> ArchiveError {
+        match self {
+            ArchiveError::Damaged { path, offset, version, file } => ArchiveError::Damaged {
+                path,
+                offset,
+                version: version.or(Some(number)),
+                file: file.or_else(|| name.map(PathBuf::from)),
+            },
+            error => error,
+        }
+    }
+
+    //Decompressing stored data only fails if reading or writing does, or if the data is damaged. Decoders
+    //report damage in different ways, so anything that isn't plainly I/O is treated as damage
+    pub(crate) fn is_damage(error: & std::io::Error) -> bool {
+        matches!(error.kind(), std::io::ErrorKind::InvalidData | std::io::ErrorKind::InvalidInput | std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::Other)
+    }
+
+    //Convert an error from decompressing the payload of the header at `offset`
+    pub(crate) fn decompression(path: & Path, offset: u64) -> impl FnOnce(std::io::Error) -> ArchiveError + '_ {
+        move |source| {
+            //Errors raised by the archive's own readers are passed through as they are
+            if source.get_ref().is_some_and(|inner| inner.is::<ArchiveError>()) {
+                return *source.into_inner().and_then(|inner| inner.downcast().ok()).expect("wraps an ArchiveError");
+            }
+
+            if ArchiveError::is_damage(&source) {
+                ArchiveError::damaged(path, offset)
+            } else {
+                ArchiveError::Io { path: PathBuf::from(path), offset: Some(offset), source }
+            }
+        }
+    }
+
+    pub(crate) fn compression<E: fmt::Debug>(path: & Path, offset: u64) -> impl FnOnce(E) -> ArchiveError + '_ {
+        move |error| ArchiveError::Compression { path: PathBuf::from(path), offset, reason: format!("{:?}", error) }
+    }
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(& self, f: & mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveError::Io { path, offset: Some(offset), source } => write!(f, "I/O error in '{}' at offset {}: {}", path.display(), offset, source),
+            ArchiveError::Io { path, offset: None, source } => write!(f, "I/O error in '{}': {}", path.display(), source),
+            ArchiveError::Corrupt { path, offset, reason } => write!(f, "archive '{}' is corrupt at offset {}: {}", path.display(), offset, reason),
+            ArchiveError::NotAnArchive(path) => write!(f, "'{}' is not an archive", path.display()),
+            ArchiveError::UnsupportedVersion { path, major, minor } => write!(f, "archive '{}' has unsupported format version {}.{}", path.display(), major, minor),
+            ArchiveError::UnsupportedFeatures { path, flags } => write!(f, "archive '{}' requires unsupported features {:#x}", path.display(), flags),
+            ArchiveError::Damaged { path, offset, version, file } => {
+                write!(f, "archive '{}' is damaged at offset {}", path.display(), offset)?;
+
+                match (version, file) {
+                    (Some(version), Some(file)) => write!(f, " (version {}, '{}')", version, file.display()),
+                    (Some(version), None) => write!(f, " (version {})", version),
+                    _ => Ok(()),
+                }
+            }
+            ArchiveError::Decode { path, offset, source } => write!(f, "could not decode header in '{}' at offset {}: {}", path.display(), offset, source),
+            ArchiveError::Compression { path, offset, reason } => write!(f, "compression error in '{}' at offset {}: {}", path.display(), offset, reason),
+            ArchiveError::InvalidPath(path) => write!(f, "invalid path '{}', appended paths must be relative and stay inside the source root", path.display()),
+            ArchiveError::VersionNotFound(version) => write!(f, "version {} not found", version),
+            ArchiveError::FileNotFound { version, path } => write!(f, "'{}' not found in version {}", path.display(), version),
+            ArchiveError::InvalidPattern { pattern, reason } => write!(f, "invalid pattern '{}': {}", pattern, reason),
+            ArchiveError::UnsupportedCompression(compression) => write!(f, "{} compression is not enabled in this build", compression),
+            ArchiveError::VersionOrder { path, number, latest } => write!(f, "cannot append version {} to '{}', version numbers must be greater than the latest ({})", number, path.display(), latest),
+            ArchiveError::LinkInPath { path, link } => write!(f, "refusing to extract '{}' through the symbolic link '{}'", path.display(), link.display()),
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {
+    fn source(& self) -> Option<& (dyn std::error::Error + 'static)> {
+        match self {
+            ArchiveError::Io { source, .. } => Some(source),
+            ArchiveError::Decode { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
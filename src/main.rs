@@ -1,36 +1,254 @@
-#![feature(seek_stream_len)]
-
-mod archive;
-
-use archive::{Archive, VersionNumber};
-
-fn main() {
-
-    let mut archive = Archive::new("E:\\Software Projects\\IntelliJ\\gud_archive\\test");
-
-    archive.create();
-
-    let mut appender = archive.appender(VersionNumber{number: 133}, String::from("Initial things"));
-
-    use std::env::{current_dir, set_current_dir};
-
-    let current = current_dir().unwrap();
-
-    set_current_dir("E:\\Software Projects\\IntelliJ\\gud_archive").unwrap();
-
-    appender.append_snapshot("a.txt");
-    appender.append_snapshot("b.txt");
-    appender.finish();
-
-    set_current_dir(current).unwrap();
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use gud_archive::archive::{Archive, ReadArchive, VersionNumber, FileType, Compression, CompressionRules, PatchPolicy, ChangeDetection, ChangeKind};
+
+const USAGE: &str = "usage:
+    gud_archive create <archive>
+    gud_archive append <archive> <number | next> <message> [options] [file or directory]...
+    gud_archive list <archive> [version]
+    gud_archive cat <archive> <version> <path>
+    gud_archive restore <archive> <version> <path> <destination>
+    gud_archive extract <archive> <version> <destination>
+    gud_archive diff <archive> <old version> <new version> [--text]
+    gud_archive log <archive> <path>
+    gud_archive verify <archive>
+
+append options:
+    --compression <method>    compress with stored, lzma, xz, deflate or zstd
+    --store <pattern>         store paths matching the glob pattern uncompressed
+    --compress <pattern>      always compress paths matching the glob pattern
+    --chunk-from <bytes>      split files at least this long into chunks, 0 never chunks
+    --incremental <check>     keep every entry of the previous version, storing files again only
+                              if they changed by 'metadata' (length and time) or by 'hash'.
+                              Directories appended this way record what was removed from them
+    --remove <path>           record that the path was removed, use with --incremental
+
+diff options:
+    --text                    show a unified diff of every modified text file
+
+versions are given by number, or as 'latest'";
+
+//Parse a version argument, which is either a version number or 'latest'
+fn parse_version(reader: & ReadArchive, argument: & str) -> Result<VersionNumber, Box<dyn std::error::Error>> {
+    match argument {
+        "latest" => reader.latest().ok_or_else(|| "the archive has no versions".into()),
+        number => Ok(VersionNumber { number: number.parse()? }),
+    }
+}
 
-    let mut reader = archive.reader();
+//Parse a compression method by the name it is displayed with
+fn parse_compression(argument: & str) -> Result<Compression, Box<dyn std::error::Error>> {
+    [Compression::Stored, Compression::Lzma, Compression::Xz, Compression::Deflate, Compression::Zstd].iter()
+        .find(|compression| compression.to_string() == argument)
+        .copied()
+        .ok_or_else(|| format!("unknown compression method '{}'", argument).into())
+}
 
-    let mut s = Vec::new();
+//The regular files of a version
+fn regular_files(reader: & mut ReadArchive, version: VersionNumber) -> Result<HashSet<PathBuf>, Box<dyn std::error::Error>> {
+    Ok(reader.entries(version)?
+        .filter(|entry| !entry.deleted && entry.file_type == FileType::File)
+        .map(|entry| PathBuf::from(entry.path))
+        .collect())
+}
 
-    let mut taken = reader.file(0, "a.txt", & mut s).unwrap();
+//The contents of a file as text, or None if it doesn't look like text
+fn text(reader: & mut ReadArchive, version: VersionNumber, path: & Path) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let mut contents = Vec::new();
+    reader.file(version, path, & mut contents)?;
 
-    println!("{}", String::from_utf8(s).unwrap());
+    Ok(String::from_utf8(contents).ok().filter(|text| !text.contains('\0')))
+}
 
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+
+    //Log to stderr, so nothing is mixed into file contents written to stdout. Set RUST_LOG to see more
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["create", archive] => {
+            Archive::new(archive).create()?;
+        }
+        ["append", archive, number, message, files @ ..] if !files.is_empty() => {
+            let mut archive = Archive::new(archive);
+
+            let mut files = files;
+            let mut compression = None;
+            let mut rules = CompressionRules::default();
+            let mut policy = PatchPolicy::default();
+            let mut incremental = None;
+            let mut removed = Vec::new();
+
+            loop {
+                match files {
+                    ["--compression", method, ..] => compression = Some(parse_compression(method)?),
+                    ["--store", pattern, ..] => rules = rules.exclude(pattern)?,
+                    ["--compress", pattern, ..] => rules = rules.include(pattern)?,
+                    ["--chunk-from", len, ..] => policy.min_chunked_len = len.parse()?,
+                    ["--incremental", "metadata", ..] => incremental = Some(ChangeDetection::Metadata),
+                    ["--incremental", "hash", ..] => incremental = Some(ChangeDetection::Hash),
+                    ["--incremental", ..] => {
+                        eprintln!("{}", USAGE);
+                        std::process::exit(2);
+                    }
+                    ["--remove", path, ..] => removed.push(*path),
+                    _ => break,
+                }
+
+                files = &files[2..];
+            }
+
+            if files.is_empty() && removed.is_empty() {
+                eprintln!("{}", USAGE);
+                std::process::exit(2);
+            }
+
+            let mut appender = match *number {
+                "next" => archive.appender_next(String::from(*message))?,
+                number => archive.appender(VersionNumber { number: number.parse()? }, String::from(*message))?,
+            };
+
+            if let Some(compression) = compression {
+                appender.set_compression(compression)?;
+            }
+
+            appender.set_rules(rules);
+            appender.set_policy(policy);
+
+            if let Some(detection) = incremental {
+                appender.set_incremental(detection);
+            }
+
+            for path in removed {
+                appender.remove(path)?;
+            }
+
+            for file in files {
+                if std::path::Path::new(file).is_dir() {
+                    appender.append_tree(file)?;
+                } else {
+                    appender.append(file)?;
+                }
+            }
+
+            appender.finish()?;
+        }
+        ["list", archive] => {
+            let mut reader = Archive::new(archive).reader()?;
+
+            for version in reader.versions()? {
+                println!("{:>8} {:>6} entries  {}", version.number, version.entries, version.message);
+            }
+        }
+        ["list", archive, version] => {
+            let mut reader = Archive::new(archive).reader()?;
+            let version = parse_version(&reader, version)?;
+
+            for entry in reader.entries(version)? {
+                let kind = match entry.file_type {
+                    _ if entry.deleted => '-',
+                    FileType::File => 'f',
+                    FileType::Directory => 'd',
+                    FileType::SystemLink => 'l',
+                };
+
+                let modified = entry.modified
+                    .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map_or(0, |modified| modified.as_secs());
+
+                //Chunks are compressed one by one, so there is no single method to show
+                let compression = match entry.chunks {
+                    0 => entry.compression.to_string(),
+                    _ => "chunked".to_string(),
+                };
+
+                match entry.link_target {
+                    _ if entry.deleted => println!("{} {:>12} {:>12} {:<7} {:>12}  {} (deleted)", kind, "", "", "", modified, entry.path.display()),
+                    Some(target) => println!("{} {:>12} {:>12} {:<7} {:>12}  {} -> {}", kind, entry.len, entry.compressed_size, compression, modified, entry.path.display(), target.display()),
+                    None => println!("{} {:>12} {:>12} {:<7} {:>12}  {}", kind, entry.len, entry.compressed_size, compression, modified, entry.path.display()),
+                }
+            }
+        }
+        ["cat", archive, version, path] => {
+            let mut reader = Archive::new(archive).reader()?;
+            let version = parse_version(&reader, version)?;
+
+            std::io::copy(& mut reader.open_file(version, path)?, & mut std::io::stdout().lock())?;
+        }
+        ["restore", archive, version, path, destination] => {
+            let mut reader = Archive::new(archive).reader()?;
+
+            reader.restore(parse_version(&reader, version)?, path, destination)?;
+        }
+        ["extract", archive, version, destination] => {
+            let mut reader = Archive::new(archive).reader()?;
+
+            reader.extract_version(parse_version(&reader, version)?, destination)?;
+        }
+        ["diff", archive, old, new, options @ ..] if options.is_empty() || options == ["--text"] => {
+            let mut reader = Archive::new(archive).reader()?;
+            let old = parse_version(&reader, old)?;
+            let new = parse_version(&reader, new)?;
+
+            let changes = reader.diff(old, new)?;
+
+            for change in changes.iter() {
+                println!("{:<9} {}", change.kind, change.path.display());
+            }
+
+            if options.is_empty() {
+                return Ok(());
+            }
+
+            //Only files that are regular files in both versions are compared as text
+            let old_files = regular_files(& mut reader, old)?;
+            let new_files = regular_files(& mut reader, new)?;
+
+            for change in changes.iter().filter(|change| change.kind == ChangeKind::Modified) {
+                if !old_files.contains(&change.path) || !new_files.contains(&change.path) {
+                    continue;
+                }
+
+                if let (Some(old_text), Some(new_text)) = (text(& mut reader, old, &change.path)?, text(& mut reader, new, &change.path)?) {
+                    let name = change.path.display();
+
+                    print!("\n{}", similar::TextDiff::from_lines(&old_text, &new_text).unified_diff()
+                        .header(&format!("a/{} (version {})", name, old), &format!("b/{} (version {})", name, new)));
+                }
+            }
+        }
+        ["log", archive, path] => {
+            let mut reader = Archive::new(archive).reader()?;
+
+            for revision in reader.history(path)? {
+                let modified = revision.modified
+                    .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map_or(0, |modified| modified.as_secs());
+
+                println!("{:>8} {:<9} {:>12} {:>12}  {}", revision.version.number, revision.kind, revision.len, modified, revision.message);
+            }
+        }
+        ["verify", archive] => {
+            let report = Archive::new(archive).verify()?;
+
+            for problem in report.problems.iter() {
+                println!("{}", problem);
+            }
+
+            println!("{} versions, {} files checked, {} problems", report.versions, report.files, report.problems.len());
+
+            if !report.is_healthy() {
+                std::process::exit(1);
+            }
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+
+    Ok(())
 
 }